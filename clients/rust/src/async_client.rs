@@ -0,0 +1,294 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use tokio::{sync::oneshot, time::Instant};
+use xtables_protobuf::protobuf::{GetDataCommand, ListChannelsCommand, reply, request};
+use zmq::{
+    Context,
+    SocketType::{DEALER, PULL, PUSH},
+};
+
+use crate::{
+    connection::{self, PendingReplies, PendingReply, ReplySender},
+    endpoint,
+    error::XTablesError,
+    ports::XTablesPorts,
+    topic,
+    update::{ChannelInfo, Update},
+    value::XTablesValue,
+    xtables_client::{PUSH_LINGER_MS, XTablesClient},
+};
+
+/// How long `set` sleeps before retrying while the push queue is full.
+const PUSH_RETRY_INTERVAL: Duration = Duration::from_millis(1);
+
+/// A client whose calls never block the thread they are awaited on, for use inside a tokio
+/// runtime. Connect it with [`XTablesClientBuilder::connect_async`].
+///
+/// Every call gives up with [`XTablesError::Timeout`] after the builder's `request_timeout`.
+/// The futures are cancellation safe: dropping one stops waiting for its reply, though a
+/// request that was already sent is still handled by the server.
+///
+/// [`XTablesClientBuilder::connect_async`]: crate::xtables_client::XTablesClientBuilder::connect_async
+pub struct AsyncXTablesClient {
+    push_socket: Mutex<zmq::Socket>,
+    /// Hands encoded requests to the reply thread, which owns the DEALER socket.
+    outbox: Mutex<zmq::Socket>,
+    pending_replies: PendingReplies<oneshot::Sender<reply::Payload>>,
+    next_request_id: AtomicU64,
+    admin_token: String,
+    timeout: Duration,
+    stop: Arc<AtomicBool>,
+    reply_thread: Option<JoinHandle<()>>,
+}
+
+impl AsyncXTablesClient {
+    pub(crate) fn connect(
+        host: &str,
+        ports: XTablesPorts,
+        admin_token: String,
+        timeout: Duration,
+    ) -> Result<Self, XTablesError> {
+        let context = Context::new();
+
+        let push_socket = context.socket(PUSH)?;
+        let dealer_socket = context.socket(DEALER)?;
+        let outbox = context.socket(PUSH)?;
+        let inbox = context.socket(PULL)?;
+
+        push_socket.set_sndhwm(500)?;
+        push_socket.set_linger(PUSH_LINGER_MS)?;
+        dealer_socket.set_linger(0)?;
+        // requests are only queued on a live connection, see `connection::forward`
+        dealer_socket.set_immediate(true)?;
+        outbox.set_linger(0)?;
+
+        if host.starts_with('[') {
+            for socket in [&push_socket, &dealer_socket] {
+                socket.set_ipv6(true)?;
+            }
+        }
+
+        push_socket.connect(&endpoint::tcp_endpoint(host, ports.push))?;
+        dealer_socket.connect(&endpoint::tcp_endpoint(host, ports.req))?;
+        // each client has its own context, so the name only has to be unique within it
+        inbox.bind("inproc://requests")?;
+        outbox.connect("inproc://requests")?;
+
+        let pending_replies: PendingReplies<_> = Arc::new(Mutex::new(HashMap::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let reply_thread = {
+            let pending_replies = Arc::clone(&pending_replies);
+            let stop = Arc::clone(&stop);
+            // the async client keeps its DEALER for good
+            let (_, replacements) = mpsc::channel();
+            thread::Builder::new()
+                .name("xtables-client-async".to_string())
+                .spawn(move || {
+                    connection::forward(dealer_socket, inbox, replacements, pending_replies, stop)
+                })
+                .expect("Failed to spawn XTables reply thread")
+        };
+
+        Ok(AsyncXTablesClient {
+            push_socket: Mutex::new(push_socket),
+            outbox: Mutex::new(outbox),
+            pending_replies,
+            next_request_id: AtomicU64::new(0),
+            admin_token,
+            timeout,
+            stop,
+            reply_thread: Some(reply_thread),
+        })
+    }
+
+    async fn request(&self, payload: request::Payload) -> Result<reply::Payload, XTablesError> {
+        let id = self.next_request_id.fetch_add(1, Ordering::SeqCst);
+        let (sender, receiver) = oneshot::channel();
+        self.pending_replies.lock().unwrap().insert(id, sender);
+        let _pending = PendingReply {
+            id,
+            pending_replies: Arc::clone(&self.pending_replies),
+        };
+
+        let message = XTablesClient::encode_request(id, &self.admin_token, payload);
+        // fails with EAGAIN instead of blocking the runtime while the reply thread falls behind
+        self.outbox.lock().unwrap().send(message, zmq::DONTWAIT)?;
+
+        match tokio::time::timeout(self.timeout, receiver).await {
+            Ok(Ok(payload)) => XTablesClient::server_result(payload),
+            // the sender is only dropped once the client shuts down
+            Ok(Err(_)) | Err(_) => Err(XTablesError::Timeout),
+        }
+    }
+
+    /// Sends any [`XTablesValue`] without waiting for the server, like
+    /// [`XTablesClient::set`]. Waits for room while the send queue is full.
+    pub async fn set<T: XTablesValue>(&self, channel: &str, value: T) -> Result<(), XTablesError> {
+        topic::validate_channel(channel)?;
+        let message = XTablesClient::push_data(channel, value.into_kind(), None);
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            match self
+                .push_socket
+                .lock()
+                .unwrap()
+                .send(&message[..], zmq::DONTWAIT)
+            {
+                Err(zmq::Error::EAGAIN) => {}
+                result => return Ok(result?),
+            }
+            if Instant::now() >= deadline {
+                return Err(XTablesError::Timeout);
+            }
+            tokio::time::sleep(PUSH_RETRY_INTERVAL).await;
+        }
+    }
+
+    /// Like `set`, but waits for the server to store the value and returns the sequence
+    /// number it was assigned, see [`XTablesClient::set_acked`].
+    pub async fn set_acked<T: XTablesValue>(
+        &self,
+        channel: &str,
+        value: T,
+    ) -> Result<u64, XTablesError> {
+        topic::validate_channel(channel)?;
+        let payload = self
+            .request(XTablesClient::set_request(channel, value.into_kind()))
+            .await?;
+        XTablesClient::acked_sequence(channel, payload)
+    }
+
+    /// The latest value of `channel`, `None` if the channel does not exist or holds no value.
+    /// Fails with [`XTablesError::TypeMismatch`] if the value is not a `T`.
+    pub async fn get<T: XTablesValue>(&self, channel: &str) -> Result<Option<T>, XTablesError> {
+        self.get_update(channel)
+            .await?
+            .map(|update| T::from_kind(update.value))
+            .transpose()
+    }
+
+    /// Like `get`, but also returns the sequence number and timestamps of the value.
+    pub async fn get_update(&self, channel: &str) -> Result<Option<Update>, XTablesError> {
+        let payload = self
+            .request(request::Payload::Data(GetDataCommand {
+                channel: channel.to_string(),
+            }))
+            .await?;
+        XTablesClient::data_reply(channel, payload)
+    }
+
+    /// Channels the server knows whose name starts with `prefix`, sorted by name.
+    pub async fn list_channels(&self, prefix: &str) -> Result<Vec<ChannelInfo>, XTablesError> {
+        let payload = self
+            .request(request::Payload::Channels(ListChannelsCommand {
+                prefix: prefix.to_string(),
+            }))
+            .await?;
+        XTablesClient::channels_reply(payload)
+    }
+}
+
+impl ReplySender for oneshot::Sender<reply::Payload> {
+    fn deliver(self, payload: reply::Payload) {
+        let _ = self.send(payload);
+    }
+}
+
+impl Drop for AsyncXTablesClient {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.reply_thread.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use prost::Message;
+    use xtables_protobuf::protobuf::Request;
+
+    /// A client whose server never answers.
+    fn unanswered(timeout: Duration, push: u16) -> AsyncXTablesClient {
+        let ports = XTablesPorts {
+            push,
+            req: push + 1,
+            sub: push + 2,
+        };
+        AsyncXTablesClient::connect("127.0.0.1", ports, String::new(), timeout).unwrap()
+    }
+
+    #[tokio::test]
+    async fn requests_time_out() {
+        let client = unanswered(Duration::from_millis(50), 49641);
+        client.set("drive/speed", 1.5).await.unwrap();
+
+        let started = Instant::now();
+        let result = client.get::<f64>("drive/speed").await;
+        assert!(matches!(result, Err(XTablesError::Timeout)));
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert!(client.pending_replies.lock().unwrap().is_empty());
+
+        let dropped = Instant::now();
+        drop(client);
+        assert!(dropped.elapsed() < Duration::from_millis(PUSH_LINGER_MS as u64 * 4));
+    }
+
+    #[tokio::test]
+    async fn timed_out_requests_are_not_sent_late() {
+        let client = Arc::new(unanswered(Duration::from_millis(300), 49701));
+        let result = client.set_acked("auto/step", 3i64).await;
+        assert!(matches!(result, Err(XTablesError::Timeout)));
+
+        let context = Context::new();
+        let router = context.socket(zmq::ROUTER).unwrap();
+        router.set_rcvtimeo(2000).unwrap();
+        router.bind("tcp://127.0.0.1:49702").unwrap();
+        // long enough for the client to reconnect
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let request = {
+            let client = Arc::clone(&client);
+            tokio::spawn(async move { client.set_acked("auto/step", 4i64).await })
+        };
+
+        let frames = tokio::task::spawn_blocking(move || router.recv_multipart(0).unwrap())
+            .await
+            .unwrap();
+        let Some(request::Payload::Set(command)) = Request::decode(&frames[1][..]).unwrap().payload
+        else {
+            panic!("expected a set request");
+        };
+        assert_eq!(
+            command.value.unwrap().kind,
+            Some(xtables_protobuf::protobuf::supported_values::Kind::Int64(4))
+        );
+        request.abort();
+    }
+
+    #[tokio::test]
+    async fn cancelled_requests_are_forgotten() {
+        let client = Arc::new(unanswered(Duration::from_secs(60), 49651));
+
+        let request = {
+            let client = Arc::clone(&client);
+            tokio::spawn(async move { client.get::<f64>("drive/speed").await })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(client.pending_replies.lock().unwrap().len(), 1);
+
+        request.abort();
+        assert!(request.await.unwrap_err().is_cancelled());
+        assert!(client.pending_replies.lock().unwrap().is_empty());
+    }
+}
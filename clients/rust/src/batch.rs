@@ -0,0 +1,68 @@
+use xtables_protobuf::protobuf::{SendDataCommand, SupportedValues};
+
+use crate::{error::XTablesError, topic, value::XTablesValue, xtables_client::XTablesClient};
+
+/// Values to write together, started with [`XTablesClient::batch`]. The server stores them
+/// at once with a shared timestamp and publishes them as one message, so subscribers never
+/// see some of them updated and others not.
+#[must_use = "nothing is sent until the batch is committed"]
+pub struct Batch<'a> {
+    client: &'a XTablesClient,
+    values: Vec<SendDataCommand>,
+    /// The first invalid channel name, reported by `commit`.
+    error: Option<XTablesError>,
+}
+
+impl<'a> Batch<'a> {
+    pub(crate) fn new(client: &'a XTablesClient) -> Self {
+        Batch {
+            client,
+            values: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// Adds a value to the batch, replacing an earlier value for the same channel.
+    pub fn set<T: XTablesValue>(mut self, channel: &str, value: T) -> Self {
+        if let Err(err) = topic::validate_channel(channel) {
+            self.error.get_or_insert(err);
+            return self;
+        }
+        let value = Some(SupportedValues {
+            kind: Some(value.into_kind()),
+        });
+        match self
+            .values
+            .iter_mut()
+            .find(|command| command.channel == channel)
+        {
+            Some(command) => command.value = value,
+            None => self.values.push(SendDataCommand {
+                channel: channel.to_string(),
+                value,
+                source_timestamp: None,
+            }),
+        }
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Sends the batch. Like `set`, this does not wait for the server, which drops the whole
+    /// batch if any value does not match its channel's type.
+    pub fn commit(self) -> Result<(), XTablesError> {
+        if let Some(err) = self.error {
+            return Err(err);
+        }
+        if self.values.is_empty() {
+            return Ok(());
+        }
+        self.client.send_batch(self.values)
+    }
+}
@@ -0,0 +1,490 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use prost::Message;
+use xtables_protobuf::protobuf::{Request, reply, request};
+use zmq::{
+    Context,
+    SocketType::{DEALER, PULL, PUSH, SUB},
+};
+
+use crate::{endpoint, error::XTablesError, ports::XTablesPorts, xtables_client::XTablesClient};
+
+/// How long the I/O thread waits on its sockets before checking whether it should stop.
+pub(crate) const IO_POLL_TIMEOUT_MS: i64 = 100;
+/// Heartbeats in a row the server may miss before it is considered gone.
+const MISSED_HEARTBEATS: u32 = 3;
+
+/// Whether the client hears from the server, passed to `on_connection_change` listeners.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    /// No heartbeat arrived for a while, the server is down, restarting or unreachable.
+    Disconnected,
+}
+
+/// Where the sockets of a client connect to, so the receiver thread can replace them.
+pub(crate) struct Endpoints {
+    context: Context,
+    ipv6: bool,
+    sub: String,
+    req: String,
+}
+
+impl Endpoints {
+    pub(crate) fn new(context: Context, host: &str, ports: &XTablesPorts) -> Self {
+        Endpoints {
+            context,
+            ipv6: host.starts_with('['),
+            sub: endpoint::tcp_endpoint(host, ports.sub),
+            req: endpoint::tcp_endpoint(host, ports.req),
+        }
+    }
+
+    /// A DEALER that only queues requests while it is connected, so a request that timed out
+    /// waiting for the connection is not sent once the server shows up again.
+    pub(crate) fn dealer(&self) -> Result<zmq::Socket, XTablesError> {
+        let socket = self.context.socket(DEALER)?;
+        socket.set_linger(0)?;
+        socket.set_immediate(true)?;
+        socket.set_ipv6(self.ipv6)?;
+        socket.connect(&self.req)?;
+        Ok(socket)
+    }
+
+    /// A SUB socket without subscriptions.
+    pub(crate) fn sub(&self) -> Result<zmq::Socket, XTablesError> {
+        let socket = self.context.socket(SUB)?;
+        socket.set_linger(0)?;
+        socket.set_ipv6(self.ipv6)?;
+        socket.connect(&self.sub)?;
+        Ok(socket)
+    }
+
+    /// Requests over a new DEALER.
+    pub(crate) fn requests(&self, admin_token: String) -> Result<Requests, XTablesError> {
+        Requests::new(&self.context, self.dealer()?, admin_token)
+    }
+}
+
+/// Requests waiting for their reply, by id, with where to deliver it.
+pub(crate) type PendingReplies<S> = Arc<Mutex<HashMap<u64, S>>>;
+
+/// Hands a reply to the caller waiting for it.
+pub(crate) trait ReplySender: Send {
+    /// Drops the reply if the caller stopped waiting.
+    fn deliver(self, payload: reply::Payload);
+}
+
+impl ReplySender for mpsc::SyncSender<reply::Payload> {
+    fn deliver(self, payload: reply::Payload) {
+        let _ = self.send(payload);
+    }
+}
+
+/// Removes its request from `pending_replies` when dropped, so abandoned requests do not pile
+/// up there.
+pub(crate) struct PendingReply<S> {
+    pub(crate) id: u64,
+    pub(crate) pending_replies: PendingReplies<S>,
+}
+
+impl<S> Drop for PendingReply<S> {
+    fn drop(&mut self) {
+        self.pending_replies.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Runs on the I/O thread of a client, which owns its DEALER socket. Sends the requests
+/// handed over through `inbox` and passes replies to whoever is still waiting for them.
+/// Requests wait here while the server is unreachable and are dropped once their caller stops
+/// waiting, so they are never sent late.
+///
+/// A socket arriving on `replacements` takes over from the current one, failing every request
+/// still waiting, since the server will not answer them on the new socket.
+pub(crate) fn forward<S: ReplySender>(
+    mut dealer: zmq::Socket,
+    inbox: zmq::Socket,
+    replacements: mpsc::Receiver<zmq::Socket>,
+    pending_replies: PendingReplies<S>,
+    stop: Arc<AtomicBool>,
+) {
+    let mut unsent: VecDeque<(u64, Vec<u8>)> = VecDeque::new();
+    while !stop.load(Ordering::SeqCst) {
+        let (requested, mut replied) = {
+            let mut items = [
+                inbox.as_poll_item(zmq::POLLIN),
+                dealer.as_poll_item(if unsent.is_empty() {
+                    zmq::POLLIN
+                } else {
+                    zmq::POLLIN | zmq::POLLOUT
+                }),
+            ];
+            if zmq::poll(&mut items, IO_POLL_TIMEOUT_MS).is_err() {
+                continue;
+            }
+            (items[0].is_readable(), items[1].is_readable())
+        };
+        while let Ok(socket) = replacements.try_recv() {
+            dealer = socket;
+            replied = false;
+            unsent.clear();
+            pending_replies.lock().unwrap().clear();
+        }
+        if requested
+            && let Ok(message) = inbox.recv_bytes(0)
+            && let Ok(request) = Request::decode(&message[..])
+        {
+            unsent.push_back((request.id, message));
+        }
+        {
+            let pending_replies = pending_replies.lock().unwrap();
+            unsent.retain(|(id, _)| pending_replies.contains_key(id));
+        }
+        while let Some((_, message)) = unsent.front() {
+            match dealer.send(&message[..], zmq::DONTWAIT) {
+                Err(zmq::Error::EAGAIN) => break,
+                // any other failure leaves the caller to time out
+                _ => {
+                    unsent.pop_front();
+                }
+            }
+        }
+        if replied
+            && let Ok(bytes) = dealer.recv_bytes(0)
+            && let Some((id, payload)) = XTablesClient::decode_reply(bytes)
+            && let Some(sender) = pending_replies.lock().unwrap().remove(&id)
+        {
+            sender.deliver(payload);
+        }
+    }
+}
+
+/// The requests of a blocking client. Callers hand them to an I/O thread owning the DEALER
+/// socket and wait for their own reply, so any number of them can be in flight at once.
+pub(crate) struct Requests {
+    /// Hands encoded requests to the I/O thread.
+    outbox: Mutex<zmq::Socket>,
+    /// DEALERs for the I/O thread to switch to, see `reset`.
+    replacements: mpsc::Sender<zmq::Socket>,
+    next_id: AtomicU64,
+    pending_replies: PendingReplies<mpsc::SyncSender<reply::Payload>>,
+    stop: Arc<AtomicBool>,
+    io_thread: Option<JoinHandle<()>>,
+    admin_token: String,
+}
+
+impl Requests {
+    /// Starts the I/O thread for `socket`, which must belong to `context`.
+    pub(crate) fn new(
+        context: &Context,
+        socket: zmq::Socket,
+        admin_token: String,
+    ) -> Result<Self, XTablesError> {
+        let outbox = context.socket(PUSH)?;
+        let inbox = context.socket(PULL)?;
+        outbox.set_linger(0)?;
+        // each client has its own context, so the name only has to be unique within it
+        inbox.bind("inproc://requests")?;
+        outbox.connect("inproc://requests")?;
+
+        let (replacements, replaced) = mpsc::channel();
+        let pending_replies: PendingReplies<_> = Arc::new(Mutex::new(HashMap::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let io_thread = {
+            let pending_replies = Arc::clone(&pending_replies);
+            let stop = Arc::clone(&stop);
+            thread::Builder::new()
+                .name("xtables-client-requests".to_string())
+                .spawn(move || forward(socket, inbox, replaced, pending_replies, stop))
+                .expect("Failed to spawn XTables request thread")
+        };
+
+        Ok(Requests {
+            outbox: Mutex::new(outbox),
+            replacements,
+            next_id: AtomicU64::new(0),
+            pending_replies,
+            stop,
+            io_thread: Some(io_thread),
+            admin_token,
+        })
+    }
+
+    /// Sends a request and waits for the reply carrying the same id, giving up with
+    /// [`XTablesError::Timeout`] once `timeout` has passed.
+    pub(crate) fn request(
+        &self,
+        payload: request::Payload,
+        timeout: Option<Duration>,
+    ) -> Result<reply::Payload, XTablesError> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let (sender, receiver) = mpsc::sync_channel(1);
+        self.pending_replies.lock().unwrap().insert(id, sender);
+        let _pending = PendingReply {
+            id,
+            pending_replies: Arc::clone(&self.pending_replies),
+        };
+
+        let message = XTablesClient::encode_request(id, &self.admin_token, payload);
+        self.outbox.lock().unwrap().send(message, 0)?;
+
+        // the sender is dropped when `reset` gives up on the request
+        let payload = match timeout {
+            Some(timeout) => receiver.recv_timeout(timeout).map_err(|err| match err {
+                mpsc::RecvTimeoutError::Timeout => XTablesError::Timeout,
+                mpsc::RecvTimeoutError::Disconnected => XTablesError::ConnectionLost,
+            })?,
+            None => receiver.recv().map_err(|_| XTablesError::ConnectionLost)?,
+        };
+        XTablesClient::server_result(payload)
+    }
+
+    /// Replaces the socket after the server was lost. Requests still waiting for a reply
+    /// fail once the I/O thread switches over.
+    pub(crate) fn reset(&self, socket: zmq::Socket) {
+        // only fails once the I/O thread is gone
+        let _ = self.replacements.send(socket);
+    }
+}
+
+impl Drop for Requests {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.io_thread.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// What a heartbeat, or the lack of one, means for the connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum HeartbeatEvent {
+    /// The first heartbeat arrived.
+    Connected,
+    /// Heartbeats stopped, or a new server instance took over.
+    Lost,
+    /// Heartbeats are back after the connection was lost.
+    Restored,
+}
+
+/// Follows the heartbeats of the server on the receiver thread.
+pub(crate) struct HeartbeatMonitor {
+    /// `None` until the first heartbeat arrives, servers that predate heartbeats never send one.
+    state: Option<ConnectionState>,
+    instance: u64,
+    interval: Duration,
+    last_heartbeat: Instant,
+}
+
+impl HeartbeatMonitor {
+    pub(crate) fn new() -> Self {
+        HeartbeatMonitor {
+            state: None,
+            instance: 0,
+            interval: Duration::ZERO,
+            last_heartbeat: Instant::now(),
+        }
+    }
+
+    pub(crate) fn heartbeat(
+        &mut self,
+        instance: u64,
+        interval: Duration,
+        now: Instant,
+    ) -> Option<HeartbeatEvent> {
+        self.interval = interval;
+        self.last_heartbeat = now;
+        let event = match self.state {
+            None => HeartbeatEvent::Connected,
+            Some(ConnectionState::Disconnected) => HeartbeatEvent::Restored,
+            // the server restarted faster than it takes to miss a heartbeat
+            Some(ConnectionState::Connected) if instance != self.instance => {
+                self.state = Some(ConnectionState::Disconnected);
+                return Some(HeartbeatEvent::Lost);
+            }
+            Some(ConnectionState::Connected) => return None,
+        };
+        self.state = Some(ConnectionState::Connected);
+        self.instance = instance;
+        Some(event)
+    }
+
+    /// Reports the connection as lost once too many heartbeats were missed.
+    pub(crate) fn check(&mut self, now: Instant) -> Option<HeartbeatEvent> {
+        if self.state != Some(ConnectionState::Connected)
+            || now.duration_since(self.last_heartbeat) < self.interval * MISSED_HEARTBEATS
+        {
+            return None;
+        }
+        self.state = Some(ConnectionState::Disconnected);
+        Some(HeartbeatEvent::Lost)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{sync::Arc, thread};
+
+    use prost::Message;
+    use xtables_protobuf::protobuf::{GetLogsCommand, Reply, ReplyErrorCommand, Request};
+    use zmq::SocketType::ROUTER;
+
+    /// A ROUTER standing in for the server, with `Requests` on a DEALER connected to it.
+    fn connect(context: &Context) -> (zmq::Socket, Requests) {
+        let router = context.socket(ROUTER).unwrap();
+        router.set_rcvtimeo(2000).unwrap();
+        router.bind("inproc://server").unwrap();
+        let dealer = context.socket(DEALER).unwrap();
+        dealer.connect("inproc://server").unwrap();
+        (
+            router,
+            Requests::new(context, dealer, String::new()).unwrap(),
+        )
+    }
+
+    fn logs() -> request::Payload {
+        request::Payload::Logs(GetLogsCommand {})
+    }
+
+    /// Receives a request, returning the identity of its sender and its id.
+    fn receive(router: &zmq::Socket) -> (Vec<u8>, u64) {
+        let frames = router.recv_multipart(0).unwrap();
+        (
+            frames[0].clone(),
+            Request::decode(&frames[1][..]).unwrap().id,
+        )
+    }
+
+    /// Replies with an error carrying the request id, so callers can tell whose reply they got.
+    fn reply(router: &zmq::Socket, identity: &[u8], id: u64) {
+        let message = Reply {
+            id,
+            protocol_version: 0,
+            payload: Some(reply::Payload::Error(ReplyErrorCommand {
+                message: id.to_string(),
+            })),
+        }
+        .encode_to_vec();
+        router.send(identity, zmq::SNDMORE).unwrap();
+        router.send(message, 0).unwrap();
+    }
+
+    fn replied_to(result: Result<reply::Payload, XTablesError>) -> u64 {
+        match result {
+            Err(XTablesError::Server(id)) => id.parse().unwrap(),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn requests_are_in_flight_together() {
+        let context = Context::new();
+        let (router, requests) = connect(&context);
+        let requests = Arc::new(requests);
+
+        let callers: Vec<_> = (0..4)
+            .map(|_| {
+                let requests = Arc::clone(&requests);
+                thread::spawn(move || replied_to(requests.request(logs(), None)))
+            })
+            .collect();
+        // every caller is still waiting when the last request arrives
+        let received: Vec<_> = (0..4).map(|_| receive(&router)).collect();
+        for (identity, id) in received.iter().rev() {
+            reply(&router, identity, *id);
+        }
+
+        let mut replied: Vec<u64> = callers
+            .into_iter()
+            .map(|caller| caller.join().unwrap())
+            .collect();
+        replied.sort();
+        assert_eq!(replied, vec![0, 1, 2, 3]);
+        assert!(requests.pending_replies.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn drops_replies_of_abandoned_requests() {
+        let context = Context::new();
+        let (router, requests) = connect(&context);
+        let requests = Arc::new(requests);
+
+        let result = requests.request(logs(), Some(Duration::from_millis(100)));
+        assert!(matches!(result, Err(XTablesError::Timeout)));
+        let (identity, abandoned) = receive(&router);
+        assert!(requests.pending_replies.lock().unwrap().is_empty());
+
+        let caller = {
+            let requests = Arc::clone(&requests);
+            thread::spawn(move || replied_to(requests.request(logs(), None)))
+        };
+        let (_, next) = receive(&router);
+        // the late reply arrives ahead of the one the next request waits for
+        reply(&router, &identity, abandoned);
+        reply(&router, &identity, next);
+        assert_eq!(caller.join().unwrap(), next);
+        assert!(requests.pending_replies.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn reset_fails_waiting_requests() {
+        let context = Context::new();
+        let (router, requests) = connect(&context);
+        let requests = Arc::new(requests);
+
+        let caller = {
+            let requests = Arc::clone(&requests);
+            thread::spawn(move || requests.request(logs(), None))
+        };
+        receive(&router);
+        requests.reset(context.socket(DEALER).unwrap());
+        assert!(matches!(
+            caller.join().unwrap(),
+            Err(XTablesError::ConnectionLost)
+        ));
+    }
+
+    #[test]
+    fn heartbeats() {
+        let interval = Duration::from_millis(500);
+        let start = Instant::now();
+        let mut monitor = HeartbeatMonitor::new();
+
+        assert_eq!(monitor.check(start + interval * 10), None);
+        assert_eq!(
+            monitor.heartbeat(1, interval, start),
+            Some(HeartbeatEvent::Connected)
+        );
+        assert_eq!(monitor.heartbeat(1, interval, start + interval), None);
+        assert_eq!(monitor.check(start + interval * 3), None);
+        assert_eq!(
+            monitor.check(start + interval * 4),
+            Some(HeartbeatEvent::Lost)
+        );
+        assert_eq!(monitor.check(start + interval * 5), None);
+        assert_eq!(
+            monitor.heartbeat(2, interval, start + interval * 6),
+            Some(HeartbeatEvent::Restored)
+        );
+
+        assert_eq!(
+            monitor.heartbeat(3, interval, start + interval * 7),
+            Some(HeartbeatEvent::Lost)
+        );
+        assert_eq!(
+            monitor.heartbeat(3, interval, start + interval * 7),
+            Some(HeartbeatEvent::Restored)
+        );
+    }
+}
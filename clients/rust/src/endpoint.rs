@@ -0,0 +1,93 @@
+use std::net::IpAddr;
+
+use crate::{error::XTablesError, ports::XTablesPorts};
+
+/// Checks that `host` is an ip address or a hostname, returning it in the form zmq expects.
+pub(crate) fn normalize_host(host: &str) -> Result<String, XTablesError> {
+    let trimmed = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+
+    if let Ok(ip) = trimmed.parse::<IpAddr>() {
+        return Ok(match ip {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => format!("[{}]", ip),
+        });
+    }
+
+    let valid_label = |label: &str| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+
+    if host.is_empty() || host.len() > 253 || !host.split('.').all(valid_label) {
+        return Err(XTablesError::InvalidHost(host.to_string()));
+    }
+
+    Ok(host.to_string())
+}
+
+pub(crate) fn validate_ports(ports: &XTablesPorts) -> Result<(), XTablesError> {
+    for port in [ports.push, ports.req, ports.sub] {
+        if port == 0 {
+            return Err(XTablesError::InvalidPort(port));
+        }
+    }
+    if ports.push == ports.req || ports.push == ports.sub {
+        return Err(XTablesError::InvalidPort(ports.push));
+    }
+    if ports.req == ports.sub {
+        return Err(XTablesError::InvalidPort(ports.req));
+    }
+    Ok(())
+}
+
+pub(crate) fn tcp_endpoint(host: &str, port: u16) -> String {
+    format!("tcp://{}:{}", host, port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hosts() {
+        assert_eq!(normalize_host("10.0.0.2").unwrap(), "10.0.0.2");
+        assert_eq!(normalize_host("::1").unwrap(), "[::1]");
+        assert_eq!(normalize_host("[::1]").unwrap(), "[::1]");
+        assert_eq!(
+            normalize_host("roborio-1234-frc.local").unwrap(),
+            "roborio-1234-frc.local"
+        );
+
+        assert!(normalize_host("").is_err());
+        assert!(normalize_host("tcp://localhost").is_err());
+        assert!(normalize_host("local host").is_err());
+        assert!(normalize_host("-bad.local").is_err());
+        assert!(normalize_host("localhost:5555").is_err());
+    }
+
+    #[test]
+    fn ports() {
+        assert!(validate_ports(&XTablesPorts::default()).is_ok());
+        assert!(
+            validate_ports(&XTablesPorts {
+                push: 0,
+                ..XTablesPorts::default()
+            })
+            .is_err()
+        );
+        assert!(
+            validate_ports(&XTablesPorts {
+                push: 6000,
+                req: 6000,
+                sub: 6001,
+            })
+            .is_err()
+        );
+    }
+}
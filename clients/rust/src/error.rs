@@ -0,0 +1,77 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum XTablesError {
+    /// The host is neither an ip address nor a valid hostname.
+    InvalidHost(String),
+    /// A port is zero or shared by two sockets.
+    InvalidPort(u16),
+    /// A channel name is empty, has an empty segment or contains a wildcard.
+    InvalidChannel(String),
+    /// A subscription pattern has an empty segment or a `*` inside a segment.
+    InvalidPattern(String),
+    Zmq(zmq::Error),
+    /// A message decoded fine but is missing a required field.
+    Malformed(&'static str),
+    /// The server answered with a different kind of reply than the request asked for.
+    UnexpectedReply,
+    /// A channel holds a value of a different type than the one asked for.
+    TypeMismatch {
+        expected: &'static str,
+        found: &'static str,
+    },
+    /// The server could not handle the request.
+    Server(String),
+    /// The server did not reply in time.
+    Timeout,
+    /// The server was lost while the request waited for its reply, which may or may not have
+    /// been handled.
+    ConnectionLost,
+    /// A struct value could not be converted to or from JSON.
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for XTablesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            XTablesError::InvalidHost(host) => write!(f, "invalid host: {:?}", host),
+            XTablesError::InvalidPort(port) => write!(f, "invalid port: {}", port),
+            XTablesError::InvalidChannel(channel) => write!(f, "invalid channel: {:?}", channel),
+            XTablesError::InvalidPattern(pattern) => {
+                write!(f, "invalid channel pattern: {:?}", pattern)
+            }
+            XTablesError::Zmq(err) => write!(f, "zmq error: {}", err),
+            XTablesError::Malformed(reason) => write!(f, "malformed message: {}", reason),
+            XTablesError::UnexpectedReply => write!(f, "unexpected reply payload type received"),
+            XTablesError::TypeMismatch { expected, found } => {
+                write!(f, "type mismatch: expected {}, found {}", expected, found)
+            }
+            XTablesError::Server(message) => write!(f, "server error: {}", message),
+            XTablesError::Timeout => write!(f, "timed out waiting for the server"),
+            XTablesError::ConnectionLost => write!(f, "lost the connection to the server"),
+            XTablesError::Json(err) => write!(f, "json error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for XTablesError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            XTablesError::Zmq(err) => Some(err),
+            XTablesError::Json(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<zmq::Error> for XTablesError {
+    fn from(err: zmq::Error) -> Self {
+        XTablesError::Zmq(err)
+    }
+}
+
+impl From<serde_json::Error> for XTablesError {
+    fn from(err: serde_json::Error) -> Self {
+        XTablesError::Json(err)
+    }
+}
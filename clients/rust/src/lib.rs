@@ -1,5 +1,15 @@
 #![allow(dead_code)]
 
-mod ports;
+pub mod async_client;
+pub mod batch;
+pub mod connection;
+mod endpoint;
+pub mod error;
+pub mod ports;
+pub mod retention;
+pub mod topic;
+pub mod update;
+pub mod value;
+pub mod watch;
 
 pub mod xtables_client;
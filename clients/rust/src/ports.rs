@@ -1,3 +1,21 @@
 pub const DEFAULT_REQ_REP_PORT: u16 = 5556;
 pub const DEFAULT_PUB_SUB_PORT: u16 = 5555;
 pub const DEFAULT_PUSH_PULL_PORT: u16 = 5557;
+
+/// Ports of the three server sockets a client connects to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct XTablesPorts {
+    pub push: u16,
+    pub req: u16,
+    pub sub: u16,
+}
+
+impl Default for XTablesPorts {
+    fn default() -> Self {
+        XTablesPorts {
+            push: DEFAULT_PUSH_PULL_PORT,
+            req: DEFAULT_REQ_REP_PORT,
+            sub: DEFAULT_PUB_SUB_PORT,
+        }
+    }
+}
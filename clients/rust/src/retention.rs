@@ -0,0 +1,12 @@
+use std::time::Duration;
+
+/// How many values the server keeps in the history of a channel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RetentionPolicy {
+    /// The most recent values, at most this many.
+    Count(u32),
+    /// Values received within this long. The latest value is always kept.
+    Window(Duration),
+    /// Only the latest value.
+    Latest,
+}
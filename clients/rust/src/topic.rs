@@ -0,0 +1,165 @@
+//! Channel names are `/` separated paths such as `drive/left/speed`.
+
+pub(crate) use xtables_protobuf::topic::{BATCH_TOPIC, HEARTBEAT_TOPIC, LOG_TOPIC, REMOVAL_TOPIC};
+use xtables_protobuf::topic::{is_valid_channel, is_valid_segment};
+
+use crate::error::XTablesError;
+
+/// Checks that `channel` is made of non-empty segments without wildcards.
+pub(crate) fn validate_channel(channel: &str) -> Result<(), XTablesError> {
+    if !is_valid_channel(channel) {
+        return Err(XTablesError::InvalidChannel(channel.to_string()));
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    /// `*`, exactly one segment.
+    One,
+    /// `**`, any number of segments including none.
+    Any,
+}
+
+/// A channel name whose segments may be `*`, matching exactly one segment, or `**`, matching
+/// any number of them. `drive/*` matches `drive/left` but not `drive/left/speed`, while
+/// `vision/**` matches `vision` and everything below it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChannelPattern {
+    pattern: String,
+    segments: Vec<Segment>,
+}
+
+impl ChannelPattern {
+    pub fn parse(pattern: &str) -> Result<Self, XTablesError> {
+        let segments = pattern
+            .split('/')
+            .map(|segment| match segment {
+                "*" => Ok(Segment::One),
+                "**" => Ok(Segment::Any),
+                segment if is_valid_segment(segment) => Ok(Segment::Literal(segment.to_string())),
+                _ => Err(XTablesError::InvalidPattern(pattern.to_string())),
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(ChannelPattern {
+            pattern: pattern.to_string(),
+            segments,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Whether the pattern names a single channel.
+    pub fn is_exact(&self) -> bool {
+        self.segments
+            .iter()
+            .all(|segment| matches!(segment, Segment::Literal(_)))
+    }
+
+    pub fn matches(&self, channel: &str) -> bool {
+        let channel: Vec<&str> = channel.split('/').collect();
+        Self::matches_segments(&self.segments, &channel)
+    }
+
+    fn matches_segments(pattern: &[Segment], channel: &[&str]) -> bool {
+        match pattern.split_first() {
+            None => channel.is_empty(),
+            Some((Segment::Any, rest)) => {
+                (0..=channel.len()).any(|skip| Self::matches_segments(rest, &channel[skip..]))
+            }
+            Some((Segment::One, rest)) => {
+                !channel.is_empty() && Self::matches_segments(rest, &channel[1..])
+            }
+            Some((Segment::Literal(literal), rest)) => {
+                channel.first() == Some(&literal.as_str())
+                    && Self::matches_segments(rest, &channel[1..])
+            }
+        }
+    }
+
+    /// The segments before the first wildcard, every matching channel starts with them.
+    pub fn literal_prefix(&self) -> String {
+        self.segments
+            .iter()
+            .map_while(|segment| match segment {
+                Segment::Literal(literal) => Some(literal.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Topic prefixes to subscribe the SUB socket to. Values are published under the bare
+    /// channel name, so they let through channels the pattern does not match, such as
+    /// `drivetrain` for `drive`, which dispatch filters out, but never miss one.
+    pub(crate) fn subscriptions(&self) -> Vec<String> {
+        let prefix = self.literal_prefix();
+        let wildcard = self
+            .segments
+            .iter()
+            .find(|segment| !matches!(segment, Segment::Literal(_)));
+
+        match wildcard {
+            None | Some(Segment::Any) => vec![prefix],
+            Some(_) if prefix.is_empty() => vec![String::new()],
+            Some(_) => vec![format!("{}/", prefix)],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channels() {
+        assert!(validate_channel("drive").is_ok());
+        assert!(validate_channel("drive/left/speed").is_ok());
+
+        assert!(validate_channel("").is_err());
+        assert!(validate_channel("/drive").is_err());
+        assert!(validate_channel("drive/").is_err());
+        assert!(validate_channel("drive//left").is_err());
+        assert!(validate_channel("drive/*").is_err());
+    }
+
+    #[test]
+    fn patterns() {
+        let pattern = |pattern| ChannelPattern::parse(pattern).unwrap();
+
+        assert!(pattern("drive").matches("drive"));
+        assert!(!pattern("drive").matches("drivetrain"));
+        assert!(!pattern("drive").matches("drive/left"));
+
+        assert!(pattern("drive/*").matches("drive/left"));
+        assert!(!pattern("drive/*").matches("drive"));
+        assert!(!pattern("drive/*").matches("drive/left/speed"));
+        assert!(!pattern("drive/*").matches("drivetrain/left"));
+
+        assert!(pattern("vision/**").matches("vision"));
+        assert!(pattern("vision/**").matches("vision/front/tx"));
+        assert!(!pattern("vision/**").matches("visionary"));
+
+        assert!(pattern("*/left/**").matches("drive/left"));
+        assert!(pattern("**/speed").matches("drive/left/speed"));
+        assert!(pattern("**").matches("anything/at/all"));
+
+        assert!(ChannelPattern::parse("drive/").is_err());
+        assert!(ChannelPattern::parse("drive/le*").is_err());
+    }
+
+    #[test]
+    fn subscriptions() {
+        let subscriptions = |pattern| ChannelPattern::parse(pattern).unwrap().subscriptions();
+
+        assert_eq!(subscriptions("drive"), ["drive"]);
+        assert_eq!(subscriptions("drive/*/speed"), ["drive/"]);
+        assert_eq!(subscriptions("vision/**"), ["vision"]);
+        assert_eq!(subscriptions("*/speed"), [""]);
+        assert_eq!(subscriptions("**/speed"), [""]);
+    }
+}
@@ -0,0 +1,117 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use xtables_protobuf::protobuf::{PublishDataCommand, StampedValue, supported_values};
+
+use crate::error::XTablesError;
+
+/// A value of a channel together with the metadata the server stamped it with.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Update {
+    pub channel: String,
+    pub value: supported_values::Kind,
+    /// Per channel counter, the first value pushed to a channel is 1.
+    /// A gap between two updates means values were missed in between.
+    pub sequence: u64,
+    /// Microseconds since the unix epoch at which the server received the value.
+    pub server_timestamp: u64,
+    /// Microseconds since the unix epoch at which the sender produced the value, if it said.
+    pub source_timestamp: Option<u64>,
+}
+
+impl Update {
+    /// Returns `None` if `command` carries no value.
+    pub(crate) fn from_publish(command: &PublishDataCommand) -> Option<Self> {
+        Some(Update {
+            channel: command.channel.clone(),
+            value: command.value.clone()?.kind?,
+            sequence: command.sequence,
+            server_timestamp: command.server_timestamp,
+            source_timestamp: command.source_timestamp,
+        })
+    }
+
+    pub(crate) fn from_stamped(channel: &str, stamped: StampedValue) -> Result<Self, XTablesError> {
+        Ok(Update {
+            channel: channel.to_string(),
+            value: stamped
+                .value
+                .and_then(|value| value.kind)
+                .ok_or(XTablesError::Malformed("stamped value without value"))?,
+            sequence: stamped.sequence,
+            server_timestamp: stamped.server_timestamp,
+            source_timestamp: stamped.source_timestamp,
+        })
+    }
+
+    pub fn server_time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_micros(self.server_timestamp)
+    }
+
+    pub fn source_time(&self) -> Option<SystemTime> {
+        self.source_timestamp
+            .map(|timestamp| UNIX_EPOCH + Duration::from_micros(timestamp))
+    }
+}
+
+/// Sent to `subscribe_removals` listeners when a channel is deleted or cleared.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Removal {
+    pub channel: String,
+    /// The channel itself is gone, rather than only its values.
+    pub deleted: bool,
+}
+
+/// A channel as reported by `list_channels`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChannelInfo {
+    pub channel: String,
+    /// Type of the latest value, e.g. `"double"`, or `None` if the channel holds no value.
+    pub value_type: Option<String>,
+    /// Microseconds since the unix epoch at which the server received the latest value.
+    pub last_update: Option<u64>,
+    /// Values pushed to the channel so far.
+    pub update_count: u64,
+}
+
+impl ChannelInfo {
+    pub fn last_update_time(&self) -> Option<SystemTime> {
+        self.last_update
+            .map(|timestamp| UNIX_EPOCH + Duration::from_micros(timestamp))
+    }
+}
+
+/// Outcome of `compare_and_set`, `add` and `toggle`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WriteResult {
+    /// Whether the value was written, only a compare-and-set whose expectation failed is not.
+    pub applied: bool,
+    /// The latest value of the channel afterwards, `None` if it holds no value.
+    pub current: Option<Update>,
+}
+
+/// The value type a channel accepts, as reported by `schemas`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChannelSchema {
+    pub channel: String,
+    /// Type name as in [`ChannelInfo::value_type`], e.g. `"double"`.
+    pub value_type: String,
+    /// Declared on the server rather than taken from the first value written to the channel.
+    pub declared: bool,
+}
+
+/// Selects which of the values the server still buffers for a channel `get_history` returns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HistoryQuery {
+    /// The most recent values, at most this many.
+    Last(u32),
+    /// Values whose sequence number is greater than this one.
+    SinceSequence(u64),
+    /// Values the server received at or after this time.
+    Since(SystemTime),
+}
+
+pub(crate) fn to_micros(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_micros() as u64)
+        .unwrap_or(0)
+}
@@ -0,0 +1,273 @@
+use std::collections::BTreeMap;
+
+use serde::{Serialize, de::DeserializeOwned};
+use xtables_protobuf::protobuf::{
+    BoolArray, DoubleArray, FloatArray, Int64Array, MapValue, StringArray, StructValue,
+    SupportedValues, supported_values::Kind,
+};
+
+use crate::error::XTablesError;
+
+/// A Rust type that maps onto one of the value types a channel can hold, so it can be sent
+/// with `set` and read back with `get` without matching on [`Kind`].
+pub trait XTablesValue: Sized {
+    fn into_kind(self) -> Kind;
+
+    /// Fails with [`XTablesError::TypeMismatch`] if `kind` holds a different type.
+    fn from_kind(kind: Kind) -> Result<Self, XTablesError>;
+}
+
+/// The raw value, for callers that handle every type themselves.
+impl XTablesValue for Kind {
+    fn into_kind(self) -> Kind {
+        self
+    }
+
+    fn from_kind(kind: Kind) -> Result<Self, XTablesError> {
+        Ok(kind)
+    }
+}
+
+macro_rules! xtables_value {
+    ($type:ty, $variant:ident, $name:literal) => {
+        impl XTablesValue for $type {
+            fn into_kind(self) -> Kind {
+                Kind::$variant(self)
+            }
+
+            fn from_kind(kind: Kind) -> Result<Self, XTablesError> {
+                match kind {
+                    Kind::$variant(value) => Ok(value),
+                    kind => Err(XTablesError::TypeMismatch {
+                        expected: $name,
+                        found: kind.type_name(),
+                    }),
+                }
+            }
+        }
+    };
+}
+
+xtables_value!(String, String, "string");
+xtables_value!(i32, Int32, "int32");
+xtables_value!(i64, Int64, "int64");
+xtables_value!(u32, Uint32, "uint32");
+xtables_value!(u64, Uint64, "uint64");
+xtables_value!(bool, Bool, "bool");
+xtables_value!(f64, Double, "double");
+xtables_value!(f32, Float, "float");
+xtables_value!(Vec<u8>, Bytes, "bytes");
+
+macro_rules! xtables_array {
+    ($type:ty, $variant:ident, $message:ident, $name:literal) => {
+        impl XTablesValue for Vec<$type> {
+            fn into_kind(self) -> Kind {
+                Kind::$variant($message { values: self })
+            }
+
+            fn from_kind(kind: Kind) -> Result<Self, XTablesError> {
+                match kind {
+                    Kind::$variant(array) => Ok(array.values),
+                    kind => Err(XTablesError::TypeMismatch {
+                        expected: $name,
+                        found: kind.type_name(),
+                    }),
+                }
+            }
+        }
+    };
+}
+
+xtables_array!(f64, DoubleArray, DoubleArray, "double[]");
+xtables_array!(f32, FloatArray, FloatArray, "float[]");
+xtables_array!(i64, Int64Array, Int64Array, "int64[]");
+xtables_array!(bool, BoolArray, BoolArray, "bool[]");
+xtables_array!(String, StringArray, StringArray, "string[]");
+
+/// Named fields sent as one value, so subscribers see all of them change at once. Fields can
+/// hold any [`XTablesValue`], including other records.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Record {
+    fields: BTreeMap<String, Kind>,
+}
+
+impl Record {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn field<T: XTablesValue>(mut self, name: &str, value: T) -> Self {
+        self.insert(name, value);
+        self
+    }
+
+    pub fn insert<T: XTablesValue>(&mut self, name: &str, value: T) {
+        self.fields.insert(name.to_string(), value.into_kind());
+    }
+
+    /// The field called `name`, `None` if the record has no such field.
+    pub fn get<T: XTablesValue>(&self, name: &str) -> Result<Option<T>, XTablesError> {
+        self.fields
+            .get(name)
+            .map(|kind| T::from_kind(kind.clone()))
+            .transpose()
+    }
+
+    /// Fields in name order.
+    pub fn fields(&self) -> impl Iterator<Item = (&str, &Kind)> {
+        self.fields.iter().map(|(name, kind)| (name.as_str(), kind))
+    }
+}
+
+impl XTablesValue for Record {
+    fn into_kind(self) -> Kind {
+        let fields = self
+            .fields
+            .into_iter()
+            .map(|(name, kind)| (name, SupportedValues { kind: Some(kind) }))
+            .collect();
+        Kind::MapValue(MapValue { fields })
+    }
+
+    fn from_kind(kind: Kind) -> Result<Self, XTablesError> {
+        match kind {
+            Kind::MapValue(map) => {
+                let fields = map
+                    .fields
+                    .into_iter()
+                    .map(|(name, value)| {
+                        let kind = value
+                            .kind
+                            .ok_or(XTablesError::Malformed("map field without value"))?;
+                        Ok((name, kind))
+                    })
+                    .collect::<Result<_, XTablesError>>()?;
+                Ok(Record { fields })
+            }
+            kind => Err(XTablesError::TypeMismatch {
+                expected: "map",
+                found: kind.type_name(),
+            }),
+        }
+    }
+}
+
+/// Encodes `value` as JSON, tagged with the name of its type.
+pub fn to_struct<T: Serialize + ?Sized>(value: &T) -> Result<Kind, XTablesError> {
+    Ok(Kind::StructValue(StructValue {
+        type_name: std::any::type_name::<T>().to_string(),
+        json: serde_json::to_string(value)?,
+    }))
+}
+
+/// Decodes a struct value into a `T`. The type name it was sent with is not checked, so
+/// peers can use their own type for the same shape.
+pub fn from_struct<T: DeserializeOwned>(kind: Kind) -> Result<T, XTablesError> {
+    match kind {
+        Kind::StructValue(value) => Ok(serde_json::from_str(&value.json)?),
+        kind => Err(XTablesError::TypeMismatch {
+            expected: "struct",
+            found: kind.type_name(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        assert_eq!(f64::from_kind(1.5.into_kind()).unwrap(), 1.5);
+        assert_eq!(
+            String::from_kind(String::from("auto").into_kind()).unwrap(),
+            "auto"
+        );
+        assert_eq!(
+            Vec::<u8>::from_kind(vec![1u8, 2].into_kind()).unwrap(),
+            [1, 2]
+        );
+        assert_eq!(Kind::from_kind(Kind::Bool(true)).unwrap(), Kind::Bool(true));
+        assert_eq!(
+            Vec::<f64>::from_kind(vec![0.5, 1.5].into_kind()).unwrap(),
+            [0.5, 1.5]
+        );
+        assert_eq!(
+            Vec::<String>::from_kind(vec![String::from("a")].into_kind()).unwrap(),
+            ["a"]
+        );
+    }
+
+    #[test]
+    fn structs() {
+        #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        struct Pose {
+            x: f64,
+            y: f64,
+        }
+
+        let pose = Pose { x: 1.0, y: -2.5 };
+        let kind = to_struct(&pose).unwrap();
+        match &kind {
+            Kind::StructValue(value) => assert!(value.type_name.ends_with("Pose")),
+            other => panic!("expected a struct value, got {:?}", other),
+        }
+        assert_eq!(from_struct::<Pose>(kind).unwrap(), pose);
+
+        assert!(matches!(
+            from_struct::<Pose>(Kind::Double(1.0)),
+            Err(XTablesError::TypeMismatch { .. })
+        ));
+        assert!(matches!(
+            from_struct::<Pose>(to_struct(&1).unwrap()),
+            Err(XTablesError::Json(_))
+        ));
+    }
+
+    #[test]
+    fn records() {
+        let pose = Record::new()
+            .field("x", 1.0)
+            .field("heading", 90.0)
+            .field("covariance", vec![0.1, 0.2])
+            .field("target", Record::new().field("id", 7i64));
+
+        let pose = Record::from_kind(pose.into_kind()).unwrap();
+        assert_eq!(pose.get::<f64>("x").unwrap(), Some(1.0));
+        assert_eq!(
+            pose.get::<Vec<f64>>("covariance").unwrap(),
+            Some(vec![0.1, 0.2])
+        );
+        assert_eq!(
+            pose.get::<Record>("target")
+                .unwrap()
+                .unwrap()
+                .get::<i64>("id")
+                .unwrap(),
+            Some(7)
+        );
+        assert_eq!(pose.get::<f64>("y").unwrap(), None);
+        assert!(pose.get::<String>("x").is_err());
+
+        let names: Vec<&str> = pose.fields().map(|(name, _)| name).collect();
+        assert_eq!(names, ["covariance", "heading", "target", "x"]);
+    }
+
+    #[test]
+    fn type_mismatch() {
+        match i32::from_kind(Kind::Double(1.0)) {
+            Err(XTablesError::TypeMismatch { expected, found }) => {
+                assert_eq!(expected, "int32");
+                assert_eq!(found, "double");
+            }
+            other => panic!("expected a type mismatch, got {:?}", other),
+        }
+        assert!(matches!(
+            Vec::<i64>::from_kind(vec![1.0].into_kind()),
+            Err(XTablesError::TypeMismatch {
+                expected: "int64[]",
+                found: "double[]"
+            })
+        ));
+    }
+}
@@ -0,0 +1,206 @@
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+use futures_core::Stream;
+use tokio::sync::watch;
+
+use crate::update::Update;
+
+type Unsubscribe = Box<dyn FnOnce() + Send + 'static>;
+
+struct Buffer {
+    updates: VecDeque<Update>,
+    capacity: usize,
+    /// Updates dropped since the last `take_lagged`.
+    lagged: u64,
+    waker: Option<Waker>,
+    /// The listener is gone, so no more updates will arrive.
+    closed: bool,
+}
+
+/// Updates of a channel as a [`Stream`], returned by `XTablesClient::watch`. Holds at most
+/// its capacity of updates; when a slow reader lets it fill up, the oldest are dropped and
+/// counted by [`take_lagged`](Self::take_lagged). Dropping the stream unsubscribes it, and the
+/// stream ends once the client is dropped.
+pub struct UpdateStream {
+    buffer: Arc<Mutex<Buffer>>,
+    unsubscribe: Option<Unsubscribe>,
+}
+
+/// Fills an [`UpdateStream`] from the client's receiver thread.
+pub(crate) struct StreamSender {
+    buffer: Arc<Mutex<Buffer>>,
+}
+
+impl UpdateStream {
+    pub(crate) fn channel(capacity: usize) -> (StreamSender, UpdateStream) {
+        let buffer = Arc::new(Mutex::new(Buffer {
+            updates: VecDeque::with_capacity(capacity),
+            capacity: capacity.max(1),
+            lagged: 0,
+            waker: None,
+            closed: false,
+        }));
+        let sender = StreamSender {
+            buffer: Arc::clone(&buffer),
+        };
+        let stream = UpdateStream {
+            buffer,
+            unsubscribe: None,
+        };
+        (sender, stream)
+    }
+
+    pub(crate) fn set_unsubscribe(&mut self, unsubscribe: Unsubscribe) {
+        self.unsubscribe = Some(unsubscribe);
+    }
+
+    /// How many updates were dropped because the buffer was full since the last call. The
+    /// updates still buffered are the newest ones.
+    pub fn take_lagged(&self) -> u64 {
+        std::mem::take(&mut self.buffer.lock().unwrap().lagged)
+    }
+}
+
+impl Stream for UpdateStream {
+    type Item = Update;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Update>> {
+        let mut buffer = self.buffer.lock().unwrap();
+        if let Some(update) = buffer.updates.pop_front() {
+            return Poll::Ready(Some(update));
+        }
+        if buffer.closed {
+            return Poll::Ready(None);
+        }
+        buffer.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for UpdateStream {
+    fn drop(&mut self) {
+        if let Some(unsubscribe) = self.unsubscribe.take() {
+            unsubscribe();
+        }
+    }
+}
+
+impl StreamSender {
+    pub(crate) fn send(&self, update: &Update) {
+        let mut buffer = self.buffer.lock().unwrap();
+        if buffer.updates.len() == buffer.capacity {
+            buffer.updates.pop_front();
+            buffer.lagged += 1;
+        }
+        buffer.updates.push_back(update.clone());
+        if let Some(waker) = buffer.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Drop for StreamSender {
+    fn drop(&mut self) {
+        let mut buffer = self.buffer.lock().unwrap();
+        buffer.closed = true;
+        if let Some(waker) = buffer.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// The latest update of a channel, returned by `XTablesClient::watch_latest`. Unlike
+/// [`UpdateStream`] it never lags, it only keeps the newest update. Dropping it unsubscribes
+/// it.
+pub struct LatestValue {
+    receiver: watch::Receiver<Option<Update>>,
+    unsubscribe: Option<Unsubscribe>,
+}
+
+impl LatestValue {
+    pub(crate) fn channel() -> (watch::Sender<Option<Update>>, LatestValue) {
+        let (sender, receiver) = watch::channel(None);
+        let latest = LatestValue {
+            receiver,
+            unsubscribe: None,
+        };
+        (sender, latest)
+    }
+
+    pub(crate) fn set_unsubscribe(&mut self, unsubscribe: Unsubscribe) {
+        self.unsubscribe = Some(unsubscribe);
+    }
+
+    /// The newest update received, `None` if the channel has held no value so far.
+    pub fn get(&self) -> Option<Update> {
+        self.receiver.borrow().clone()
+    }
+
+    /// Waits for an update newer than the one last seen through `changed` or
+    /// `get_and_update`. Fails once the client is dropped.
+    pub async fn changed(&mut self) -> Result<(), watch::error::RecvError> {
+        self.receiver.changed().await
+    }
+
+    /// Like `get`, but also marks the update as seen so `changed` waits for the next one.
+    pub fn get_and_update(&mut self) -> Option<Update> {
+        self.receiver.borrow_and_update().clone()
+    }
+}
+
+impl Drop for LatestValue {
+    fn drop(&mut self) {
+        if let Some(unsubscribe) = self.unsubscribe.take() {
+            unsubscribe();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use xtables_protobuf::protobuf::supported_values::Kind;
+
+    fn update(sequence: u64) -> Update {
+        Update {
+            channel: String::from("drive/speed"),
+            value: Kind::Double(sequence as f64),
+            sequence,
+            server_timestamp: 0,
+            source_timestamp: None,
+        }
+    }
+
+    fn poll(stream: &mut UpdateStream) -> Poll<Option<u64>> {
+        let mut cx = Context::from_waker(Waker::noop());
+        Pin::new(stream)
+            .poll_next(&mut cx)
+            .map(|update| update.map(|update| update.sequence))
+    }
+
+    #[test]
+    fn drops_oldest_when_full() {
+        let (sender, mut stream) = UpdateStream::channel(2);
+        assert_eq!(poll(&mut stream), Poll::Pending);
+
+        for sequence in 1..=5 {
+            sender.send(&update(sequence));
+        }
+        assert_eq!(stream.take_lagged(), 3);
+        assert_eq!(stream.take_lagged(), 0);
+        assert_eq!(poll(&mut stream), Poll::Ready(Some(4)));
+        assert_eq!(poll(&mut stream), Poll::Ready(Some(5)));
+        assert_eq!(poll(&mut stream), Poll::Pending);
+
+        sender.send(&update(6));
+        drop(sender);
+        assert_eq!(poll(&mut stream), Poll::Ready(Some(6)));
+        assert_eq!(poll(&mut stream), Poll::Ready(None));
+    }
+}
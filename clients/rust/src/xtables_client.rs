@@ -1,243 +1,1096 @@
 use std::{
     collections::HashMap,
     io::Cursor,
-    rc::Rc,
     sync::{
         Arc, Mutex,
         atomic::{AtomicBool, Ordering},
     },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime},
 };
 
 use prost::Message;
+use serde::{Serialize, de::DeserializeOwned};
 use slotmap::{DefaultKey, SlotMap};
-use tokio::task;
 
-use xtables_protobuf::protobuf::{
-    GetDataCommand, GetLogsCommand, Publish, Push, Reply, Request, SendDataCommand,
-    SupportedValues, publish, push, reply, request, supported_values,
+use xtables_protobuf::{
+    LEGACY_NO_DATA, PROTOCOL_VERSION,
+    protobuf::{
+        self, AddCommand, ClearChannelCommand, CompareAndSetCommand, DeclareTypeCommand,
+        DeleteChannelCommand, GetDataCommand, GetHistoryCommand, GetLogsCommand, GetSchemasCommand,
+        ListChannelsCommand, NotFound, Publish, Push, Reply, Request, SendBatchCommand,
+        SendDataCommand, SetRetentionCommand, SupportedValues, ToggleCommand,
+        compare_and_set_command, get_history_command, publish, push, reply, reply_data_command,
+        request, retention_policy, supported_values,
+    },
 };
 
-use zmq::{
-    Context,
-    SocketType::{PUSH, REQ, SUB},
+use zmq::{Context, SocketType::PUSH};
+
+use crate::{
+    async_client::AsyncXTablesClient,
+    batch::Batch,
+    connection::{ConnectionState, Endpoints, HeartbeatEvent, HeartbeatMonitor, Requests},
+    endpoint,
+    error::XTablesError,
+    ports::XTablesPorts,
+    retention::RetentionPolicy,
+    topic::{self, ChannelPattern},
+    update::{self, ChannelInfo, ChannelSchema, HistoryQuery, Removal, Update, WriteResult},
+    value::{self, XTablesValue},
+    watch::{LatestValue, UpdateStream},
 };
 
-use crate::ports;
+const DEFAULT_HOST: &str = "127.0.0.1";
+const DEFAULT_ACK_TIMEOUT: Duration = Duration::from_secs(1);
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Updates an `UpdateStream` of `watch` holds before dropping the oldest.
+const DEFAULT_WATCH_CAPACITY: usize = 64;
+/// How long the receiver waits for a publish before checking whether it should stop.
+const SUB_POLL_TIMEOUT_MS: i64 = 100;
+/// Bounds every request of the receiver thread, which stops receiving while it waits.
+const RECOVERY_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long the receiver waits before reading current values again after the server did not
+/// answer.
+const REFRESH_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+/// How long a dropped client keeps trying to deliver the values it still has queued, so
+/// dropping it returns even when the server is unreachable.
+pub(crate) const PUSH_LINGER_MS: i32 = 500;
+
+type SubscribeListener = Box<dyn Fn(&Update) + Send + 'static>;
+type RemovalListener = Box<dyn Fn(&Removal) + Send + 'static>;
+type BatchListener = Box<dyn Fn(&[Update]) + Send + 'static>;
+/// Listener groups keyed by the pattern they were subscribed with.
+type SubscribeListenerMap = Arc<Mutex<HashMap<String, PatternListeners>>>;
 
-const DEFAULT_REQ_PORT: u16 = ports::DEFAULT_REQ_REP_PORT;
-const DEFAULT_SUB_PORT: u16 = ports::DEFAULT_PUB_SUB_PORT;
-const DEFAULT_PUSH_PORT: u16 = ports::DEFAULT_PUSH_PULL_PORT;
+/// A listener added by `subscribe_listener`, which holds back the updates that arrive while
+/// the current values are read. `held` is `None` once those were passed on.
+struct NewListener<F> {
+    callback: F,
+    held: Option<Vec<Update>>,
+}
+
+struct PatternListeners {
+    pattern: ChannelPattern,
+    listeners: SlotMap<DefaultKey, SubscribeListener>,
+    removal_listeners: SlotMap<DefaultKey, RemovalListener>,
+    batch_listeners: SlotMap<DefaultKey, BatchListener>,
+}
 
-type SubscribeListener = Box<dyn Fn(&supported_values::Kind) + Send + 'static>;
-type SubscribeListenerMap = Arc<Mutex<HashMap<String, SlotMap<DefaultKey, SubscribeListener>>>>;
+impl PatternListeners {
+    /// Topics the subscriber socket needs for the listeners of the group. Removals and batches
+    /// are published under topics of their own, which are only subscribed while someone
+    /// listens for them.
+    fn topics(&self) -> Vec<String> {
+        let mut topics = Vec::new();
+        if !self.listeners.is_empty() {
+            topics.extend(self.pattern.subscriptions());
+        }
+        if !self.removal_listeners.is_empty() {
+            topics.push(topic::REMOVAL_TOPIC.to_string());
+        }
+        if !self.batch_listeners.is_empty() {
+            topics.push(topic::BATCH_TOPIC.to_string());
+        }
+        topics
+    }
+}
 
 type LogListener = Box<dyn Fn(&String) + Send + 'static>;
 type LogListenerMap = Arc<Mutex<SlotMap<DefaultKey, LogListener>>>;
 
+type ConnectionListener = Box<dyn Fn(ConnectionState) + Send + 'static>;
+type ConnectionListenerMap = Arc<Mutex<SlotMap<DefaultKey, ConnectionListener>>>;
+
+/// A connection to the server. The client is `Send + Sync`, so one instance can be shared
+/// between threads in an `Arc` instead of every thread opening its own sockets. Concurrent
+/// requests are told apart by their id.
 pub struct XTablesClient {
     data_listeners: SubscribeListenerMap,
     log_listeners: LogListenerMap,
-    push_socket: zmq::Socket,
+    connection_listeners: ConnectionListenerMap,
+    push_socket: Mutex<zmq::Socket>,
     sub_socket: Arc<Mutex<zmq::Socket>>,
-    req_socket: Rc<zmq::Socket>,
+    requests: Arc<Requests>,
+    endpoints: Arc<Endpoints>,
+    ack_timeout: Duration,
     stop: Arc<AtomicBool>,
-    initialized: Arc<AtomicBool>,
+    receiver: Mutex<Option<JoinHandle<()>>>,
 }
 
-impl XTablesClient {
+pub struct XTablesClientBuilder {
+    host: String,
+    ports: XTablesPorts,
+    admin_token: String,
+    ack_timeout: Duration,
+    request_timeout: Duration,
+}
+
+impl XTablesClientBuilder {
     pub fn new() -> Self {
+        XTablesClientBuilder {
+            host: String::from(DEFAULT_HOST),
+            ports: XTablesPorts::default(),
+            admin_token: String::new(),
+            ack_timeout: DEFAULT_ACK_TIMEOUT,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+        }
+    }
+
+    /// Hostname or ip address of the machine running the server.
+    pub fn host(mut self, host: &str) -> Self {
+        self.host = host.to_string();
+        self
+    }
+
+    pub fn ports(mut self, ports: XTablesPorts) -> Self {
+        self.ports = ports;
+        self
+    }
+
+    pub fn push_port(mut self, port: u16) -> Self {
+        self.ports.push = port;
+        self
+    }
+
+    pub fn req_port(mut self, port: u16) -> Self {
+        self.ports.req = port;
+        self
+    }
+
+    pub fn sub_port(mut self, port: u16) -> Self {
+        self.ports.sub = port;
+        self
+    }
+
+    /// Token the server was started with, needed to change retention and declare channel
+    /// types.
+    pub fn admin_token(mut self, token: &str) -> Self {
+        self.admin_token = token.to_string();
+        self
+    }
+
+    /// How long `set_acked` waits for the server to confirm a write, one second by default.
+    pub fn ack_timeout(mut self, timeout: Duration) -> Self {
+        self.ack_timeout = timeout;
+        self
+    }
+
+    /// How long every call of an [`AsyncXTablesClient`] waits for the server, five seconds by
+    /// default.
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// Connects an [`AsyncXTablesClient`] instead of a blocking one.
+    pub fn connect_async(self) -> Result<AsyncXTablesClient, XTablesError> {
+        let host = endpoint::normalize_host(&self.host)?;
+        endpoint::validate_ports(&self.ports)?;
+        AsyncXTablesClient::connect(&host, self.ports, self.admin_token, self.request_timeout)
+    }
+
+    pub fn connect(self) -> Result<XTablesClient, XTablesError> {
+        let host = endpoint::normalize_host(&self.host)?;
+        endpoint::validate_ports(&self.ports)?;
+
         let context = Context::new();
 
         let listeners: SubscribeListenerMap = Arc::new(Mutex::new(HashMap::new()));
         let log_listeners: LogListenerMap = Arc::new(Mutex::new(SlotMap::new()));
 
         let stop = Arc::new(AtomicBool::new(false));
-        let initialized = Arc::new(AtomicBool::new(false));
-
-        let push_socket = context.socket(PUSH).unwrap();
-        let req_socket = Rc::new(context.socket(REQ).unwrap());
-        let sub_socket = Arc::new(Mutex::new(context.socket(SUB).unwrap()));
 
-        push_socket
-            .connect(&format!("tcp://:{}", DEFAULT_PUSH_PORT))
-            .unwrap();
-
-        req_socket
-            .connect(&format!("tcp://:{}", DEFAULT_REQ_PORT))
-            .unwrap();
-
-        sub_socket
-            .lock()
-            .unwrap()
-            .connect(&format!("tcp://:{}", DEFAULT_SUB_PORT))
-            .unwrap();
+        let push_socket = context.socket(PUSH)?;
+        push_socket.set_rcvhwm(500)?;
+        push_socket.set_sndhwm(500)?;
+        push_socket.set_linger(PUSH_LINGER_MS)?;
+        push_socket.set_ipv6(host.starts_with('['))?;
+        push_socket.connect(&endpoint::tcp_endpoint(&host, self.ports.push))?;
 
-        push_socket.set_rcvhwm(500).unwrap();
-        push_socket.set_sndhwm(500).unwrap();
+        let endpoints = Endpoints::new(context, &host, &self.ports);
+        let sub_socket = endpoints.sub()?;
+        sub_socket.set_subscribe(topic::HEARTBEAT_TOPIC.as_bytes())?;
+        let requests = endpoints.requests(self.admin_token)?;
 
-        XTablesClient {
+        Ok(XTablesClient {
             data_listeners: listeners,
-            push_socket,
-            sub_socket,
-            req_socket,
+            connection_listeners: Arc::new(Mutex::new(SlotMap::new())),
+            push_socket: Mutex::new(push_socket),
+            sub_socket: Arc::new(Mutex::new(sub_socket)),
+            requests: Arc::new(requests),
+            endpoints: Arc::new(endpoints),
+            ack_timeout: self.ack_timeout,
             stop,
-            initialized,
+            receiver: Mutex::new(None),
             log_listeners,
-        }
+        })
+    }
+}
+
+impl Default for XTablesClientBuilder {
+    fn default() -> Self {
+        XTablesClientBuilder::new()
+    }
+}
+
+impl XTablesClient {
+    /// Connects to a server on this machine using the default ports, panicking on failure.
+    pub fn new() -> Self {
+        XTablesClient::builder()
+            .connect()
+            .expect("Failed to connect XTables client")
     }
 
-    fn push_data(channel: &str, data: supported_values::Kind) -> Vec<u8> {
+    pub fn builder() -> XTablesClientBuilder {
+        XTablesClientBuilder::new()
+    }
+
+    /// Connects to the server at `host`, which may be a hostname, an IPv4 or an IPv6 address.
+    pub fn connect(host: &str, ports: XTablesPorts) -> Result<Self, XTablesError> {
+        XTablesClient::builder().host(host).ports(ports).connect()
+    }
+
+    pub(crate) fn push_data(
+        channel: &str,
+        data: supported_values::Kind,
+        source_timestamp: Option<u64>,
+    ) -> Vec<u8> {
         Push {
             payload: Some(push::Payload::Send(SendDataCommand {
                 channel: channel.to_string(),
                 value: Some(SupportedValues { kind: Some(data) }),
+                source_timestamp,
             })),
         }
         .encode_to_vec()
     }
 
-    fn request_data(channel: &str) -> Vec<u8> {
+    /// Sends a request over the DEALER socket and waits for the reply carrying the same id.
+    fn request(&self, payload: request::Payload) -> Result<reply::Payload, XTablesError> {
+        self.requests.request(payload, None)
+    }
+
+    pub(crate) fn encode_request(id: u64, admin_token: &str, payload: request::Payload) -> Vec<u8> {
+        // only requests that change server settings carry the token
+        let admin_token = match payload {
+            request::Payload::Retention(_) | request::Payload::Declare(_) => admin_token,
+            _ => "",
+        };
         Request {
-            payload: Some(request::Payload::Data(GetDataCommand {
-                channel: channel.to_string(),
-            })),
+            id,
+            protocol_version: PROTOCOL_VERSION,
+            admin_token: admin_token.to_string(),
+            payload: Some(payload),
         }
         .encode_to_vec()
     }
 
-    fn request_log() -> Vec<u8> {
-        Request {
-            payload: Some(request::Payload::Logs(GetLogsCommand {})),
+    /// The id and payload of a reply, `None` if it cannot be decoded. The id is then lost with
+    /// the rest of the frame, so nobody can claim it.
+    pub(crate) fn decode_reply(bytes: Vec<u8>) -> Option<(u64, reply::Payload)> {
+        let reply = Reply::decode(Cursor::new(bytes)).ok()?;
+        let payload = Self::upgrade_reply(reply.protocol_version, reply.payload?);
+        Some((reply.id, payload))
+    }
+
+    pub(crate) fn server_result(payload: reply::Payload) -> Result<reply::Payload, XTablesError> {
+        match payload {
+            reply::Payload::Error(command) => Err(XTablesError::Server(command.message)),
+            payload => Ok(payload),
         }
-        .encode_to_vec()
     }
 
-    fn send_message(&self, channel: &str, kind: supported_values::Kind) {
-        let message = Self::push_data(channel, kind);
-        self.push_socket.send(message, 0).expect("failed to send");
+    /// Rewrites replies of servers older than [`PROTOCOL_VERSION`] into their current form.
+    fn upgrade_reply(protocol_version: u32, payload: reply::Payload) -> reply::Payload {
+        match payload {
+            reply::Payload::Data(mut command) if protocol_version < 2 => {
+                if let Some(reply_data_command::Data::Value(SupportedValues {
+                    kind: Some(supported_values::Kind::String(value)),
+                })) = &command.data
+                    && value == LEGACY_NO_DATA
+                {
+                    command.data = Some(reply_data_command::Data::NotFound(NotFound {}));
+                }
+                reply::Payload::Data(command)
+            }
+            payload => payload,
+        }
     }
 
-    pub fn send_string(&self, channel: &str, data: &str) {
-        self.send_message(channel, supported_values::Kind::String(data.to_string()));
+    /// Starts a batch of values written together, e.g.
+    /// `client.batch().set("vision/tx", 1.5).set("vision/ty", -0.5).commit()`.
+    pub fn batch(&self) -> Batch<'_> {
+        Batch::new(self)
     }
 
-    pub fn send_i32(&self, channel: &str, data: i32) {
-        self.send_message(channel, supported_values::Kind::Int32(data));
+    pub(crate) fn send_batch(&self, values: Vec<SendDataCommand>) -> Result<(), XTablesError> {
+        let message = Push {
+            payload: Some(push::Payload::Batch(SendBatchCommand { values })),
+        }
+        .encode_to_vec();
+        self.push_socket.lock().unwrap().send(message, 0)?;
+        Ok(())
     }
 
-    pub fn send_i64(&self, channel: &str, data: i64) {
-        self.send_message(channel, supported_values::Kind::Int64(data));
+    fn send_message(
+        &self,
+        channel: &str,
+        kind: supported_values::Kind,
+    ) -> Result<(), XTablesError> {
+        topic::validate_channel(channel)?;
+        let message = Self::push_data(channel, kind, None);
+        self.push_socket.lock().unwrap().send(message, 0)?;
+        Ok(())
     }
 
-    pub fn send_u32(&self, channel: &str, data: u32) {
-        self.send_message(channel, supported_values::Kind::Uint32(data));
+    /// Sends `value` stamped with the time it was produced, e.g. when a camera frame was taken.
+    /// Subscribers receive it as [`Update::source_timestamp`].
+    pub fn send_at(
+        &self,
+        channel: &str,
+        value: supported_values::Kind,
+        source_time: SystemTime,
+    ) -> Result<(), XTablesError> {
+        topic::validate_channel(channel)?;
+        let message = Self::push_data(channel, value, Some(update::to_micros(source_time)));
+        self.push_socket.lock().unwrap().send(message, 0)?;
+        Ok(())
     }
 
-    pub fn send_u64(&self, channel: &str, data: u64) {
-        self.send_message(channel, supported_values::Kind::Uint64(data));
+    pub fn send_string(&self, channel: &str, data: &str) -> Result<(), XTablesError> {
+        self.send_message(channel, supported_values::Kind::String(data.to_string()))
     }
 
-    pub fn send_bool(&self, channel: &str, data: bool) {
-        self.send_message(channel, supported_values::Kind::Bool(data));
+    pub fn send_i32(&self, channel: &str, data: i32) -> Result<(), XTablesError> {
+        self.send_message(channel, supported_values::Kind::Int32(data))
     }
 
-    pub fn send_double(&self, channel: &str, data: f64) {
-        self.send_message(channel, supported_values::Kind::Double(data));
+    pub fn send_i64(&self, channel: &str, data: i64) -> Result<(), XTablesError> {
+        self.send_message(channel, supported_values::Kind::Int64(data))
     }
 
-    pub fn send_float(&self, channel: &str, data: f32) {
-        self.send_message(channel, supported_values::Kind::Float(data));
+    pub fn send_u32(&self, channel: &str, data: u32) -> Result<(), XTablesError> {
+        self.send_message(channel, supported_values::Kind::Uint32(data))
     }
 
-    pub fn send_bytes(&self, channel: &str, data: &[u8]) {
-        self.send_message(channel, supported_values::Kind::Bytes(data.to_vec()));
+    pub fn send_u64(&self, channel: &str, data: u64) -> Result<(), XTablesError> {
+        self.send_message(channel, supported_values::Kind::Uint64(data))
     }
 
-    pub fn get(&self, channel: &str) -> supported_values::Kind {
-        let channel = channel.to_string();
-        let req_socket = self.req_socket.clone();
+    pub fn send_bool(&self, channel: &str, data: bool) -> Result<(), XTablesError> {
+        self.send_message(channel, supported_values::Kind::Bool(data))
+    }
 
-        let message = Self::request_data(&channel);
+    pub fn send_double(&self, channel: &str, data: f64) -> Result<(), XTablesError> {
+        self.send_message(channel, supported_values::Kind::Double(data))
+    }
 
-        req_socket.send(message, 0).unwrap();
-        let buffer = Cursor::new(req_socket.recv_bytes(0).unwrap());
-        let payload = Reply::decode(buffer).unwrap().payload.unwrap();
+    pub fn send_float(&self, channel: &str, data: f32) -> Result<(), XTablesError> {
+        self.send_message(channel, supported_values::Kind::Float(data))
+    }
 
-        match &payload {
-            reply::Payload::Data(command) => command
-                .value
-                .as_ref()
-                .unwrap()
-                .kind
-                .as_ref()
-                .unwrap()
-                .clone(),
+    pub fn send_bytes(&self, channel: &str, data: &[u8]) -> Result<(), XTablesError> {
+        self.send_message(channel, supported_values::Kind::Bytes(data.to_vec()))
+    }
+
+    pub fn send_double_array(&self, channel: &str, data: &[f64]) -> Result<(), XTablesError> {
+        self.set(channel, data.to_vec())
+    }
+
+    pub fn send_float_array(&self, channel: &str, data: &[f32]) -> Result<(), XTablesError> {
+        self.set(channel, data.to_vec())
+    }
+
+    pub fn send_i64_array(&self, channel: &str, data: &[i64]) -> Result<(), XTablesError> {
+        self.set(channel, data.to_vec())
+    }
+
+    pub fn send_bool_array(&self, channel: &str, data: &[bool]) -> Result<(), XTablesError> {
+        self.set(channel, data.to_vec())
+    }
+
+    pub fn send_string_array<S: AsRef<str>>(
+        &self,
+        channel: &str,
+        data: &[S],
+    ) -> Result<(), XTablesError> {
+        let data: Vec<String> = data
+            .iter()
+            .map(|value| value.as_ref().to_string())
+            .collect();
+        self.set(channel, data)
+    }
+
+    /// Sends any [`XTablesValue`], e.g. `client.set("arm/angle", 1.2)`.
+    pub fn set<T: XTablesValue>(&self, channel: &str, value: T) -> Result<(), XTablesError> {
+        self.send_message(channel, value.into_kind())
+    }
+
+    /// Like `set`, but waits for the server to store the value and returns the sequence number
+    /// it was assigned. Fails with [`XTablesError::Timeout`] if the server does not confirm
+    /// the write within the client's `ack_timeout`, e.g. because it is down. A value that
+    /// could not be sent in time is dropped, while one the server already received may still
+    /// be written.
+    pub fn set_acked<T: XTablesValue>(&self, channel: &str, value: T) -> Result<u64, XTablesError> {
+        topic::validate_channel(channel)?;
+        let payload = self.requests.request(
+            Self::set_request(channel, value.into_kind()),
+            Some(self.ack_timeout),
+        )?;
+        Self::acked_sequence(channel, payload)
+    }
+
+    pub(crate) fn set_request(channel: &str, value: supported_values::Kind) -> request::Payload {
+        request::Payload::Set(SendDataCommand {
+            channel: channel.to_string(),
+            value: Some(SupportedValues { kind: Some(value) }),
+            source_timestamp: None,
+        })
+    }
+
+    pub(crate) fn acked_sequence(
+        channel: &str,
+        payload: reply::Payload,
+    ) -> Result<u64, XTablesError> {
+        Self::write_result(channel, payload)?
+            .current
+            .map(|current| current.sequence)
+            .ok_or(XTablesError::Malformed("acknowledged write without value"))
+    }
+
+    /// The latest value of `channel`, `None` if the channel does not exist or holds no value.
+    /// Fails with [`XTablesError::TypeMismatch`] if the value is not a `T`; ask for
+    /// `supported_values::Kind` to accept any type.
+    pub fn get<T: XTablesValue>(&self, channel: &str) -> Result<Option<T>, XTablesError> {
+        self.get_update(channel)?
+            .map(|update| T::from_kind(update.value))
+            .transpose()
+    }
+
+    /// Sends any serde value, encoded as JSON so the server can log it, e.g.
+    /// `client.set_struct("pose", &pose)`.
+    pub fn set_struct<T: Serialize + ?Sized>(
+        &self,
+        channel: &str,
+        value: &T,
+    ) -> Result<(), XTablesError> {
+        self.send_message(channel, value::to_struct(value)?)
+    }
+
+    /// Reads a value sent with `set_struct`. Fails with [`XTablesError::TypeMismatch`] if the
+    /// channel holds a plain value and [`XTablesError::Json`] if it does not fit a `T`.
+    pub fn get_struct<T: DeserializeOwned>(
+        &self,
+        channel: &str,
+    ) -> Result<Option<T>, XTablesError> {
+        self.get_update(channel)?
+            .map(|update| value::from_struct(update.value))
+            .transpose()
+    }
+
+    /// Like `get`, but also returns the sequence number and timestamps of the value.
+    pub fn get_update(&self, channel: &str) -> Result<Option<Update>, XTablesError> {
+        let payload = self.request(request::Payload::Data(GetDataCommand {
+            channel: channel.to_string(),
+        }))?;
+        Self::data_reply(channel, payload)
+    }
+
+    pub(crate) fn data_reply(
+        channel: &str,
+        payload: reply::Payload,
+    ) -> Result<Option<Update>, XTablesError> {
+        match payload {
+            reply::Payload::Data(command) => {
+                let value = match command.data {
+                    Some(reply_data_command::Data::Value(value)) => value
+                        .kind
+                        .ok_or(XTablesError::Malformed("reply without value"))?,
+                    Some(reply_data_command::Data::NotFound(_)) => return Ok(None),
+                    None => return Err(XTablesError::Malformed("reply without value")),
+                };
+                Ok(Some(Update {
+                    channel: channel.to_string(),
+                    value,
+                    sequence: command.sequence,
+                    server_timestamp: command.server_timestamp,
+                    source_timestamp: command.source_timestamp,
+                }))
+            }
+
+            _ => Err(XTablesError::UnexpectedReply),
+        }
+    }
+
+    /// Writes `value` only if the latest value of `channel` equals `expected`. Unlike `set`
+    /// this waits for the server, whose result says whether the write happened.
+    pub fn compare_and_set<T: XTablesValue>(
+        &self,
+        channel: &str,
+        expected: T,
+        value: T,
+    ) -> Result<WriteResult, XTablesError> {
+        self.compare_and_set_with(
+            channel,
+            compare_and_set_command::Expected::ExpectedValue(SupportedValues {
+                kind: Some(expected.into_kind()),
+            }),
+            value.into_kind(),
+        )
+    }
+
+    /// Writes `value` only if the latest value of `channel` has the sequence number
+    /// `expected_sequence`. Pass 0 to write only if the channel holds no value, e.g. to
+    /// claim it.
+    pub fn compare_and_set_sequence<T: XTablesValue>(
+        &self,
+        channel: &str,
+        expected_sequence: u64,
+        value: T,
+    ) -> Result<WriteResult, XTablesError> {
+        self.compare_and_set_with(
+            channel,
+            compare_and_set_command::Expected::ExpectedSequence(expected_sequence),
+            value.into_kind(),
+        )
+    }
+
+    fn compare_and_set_with(
+        &self,
+        channel: &str,
+        expected: compare_and_set_command::Expected,
+        value: supported_values::Kind,
+    ) -> Result<WriteResult, XTablesError> {
+        topic::validate_channel(channel)?;
+        let payload = self.request(request::Payload::CompareAndSet(CompareAndSetCommand {
+            channel: channel.to_string(),
+            value: Some(SupportedValues { kind: Some(value) }),
+            expected: Some(expected),
+        }))?;
+        Self::write_result(channel, payload)
+    }
+
+    /// Adds `delta` to the latest value of `channel` on the server, so concurrent increments
+    /// are never lost. The value must have the same numeric type as `delta`, and an empty
+    /// channel counts as zero.
+    pub fn add<T: XTablesValue>(
+        &self,
+        channel: &str,
+        delta: T,
+    ) -> Result<WriteResult, XTablesError> {
+        topic::validate_channel(channel)?;
+        let payload = self.request(request::Payload::Add(AddCommand {
+            channel: channel.to_string(),
+            delta: Some(SupportedValues {
+                kind: Some(delta.into_kind()),
+            }),
+        }))?;
+        Self::write_result(channel, payload)
+    }
+
+    /// Negates the bool value of `channel` on the server. An empty channel counts as false.
+    pub fn toggle(&self, channel: &str) -> Result<WriteResult, XTablesError> {
+        topic::validate_channel(channel)?;
+        let payload = self.request(request::Payload::Toggle(ToggleCommand {
+            channel: channel.to_string(),
+        }))?;
+        Self::write_result(channel, payload)
+    }
+
+    pub(crate) fn write_result(
+        channel: &str,
+        payload: reply::Payload,
+    ) -> Result<WriteResult, XTablesError> {
+        match payload {
+            reply::Payload::Write(command) => Ok(WriteResult {
+                applied: command.applied,
+                current: command
+                    .current
+                    .map(|current| Update::from_stamped(channel, current))
+                    .transpose()?,
+            }),
+
+            _ => Err(XTablesError::UnexpectedReply),
+        }
+    }
+
+    /// Values of `channel` still buffered on the server, oldest first. Works without having
+    /// subscribed to the channel beforehand.
+    pub fn get_history(
+        &self,
+        channel: &str,
+        query: HistoryQuery,
+    ) -> Result<Vec<Update>, XTablesError> {
+        let query = match query {
+            HistoryQuery::Last(count) => get_history_command::Query::Last(count),
+            HistoryQuery::SinceSequence(sequence) => {
+                get_history_command::Query::SinceSequence(sequence)
+            }
+            HistoryQuery::Since(time) => {
+                get_history_command::Query::SinceTimestamp(update::to_micros(time))
+            }
+        };
+        let payload = self.request(request::Payload::History(GetHistoryCommand {
+            channel: channel.to_string(),
+            query: Some(query),
+        }))?;
+
+        match payload {
+            reply::Payload::History(command) => command
+                .values
+                .into_iter()
+                .map(|stamped| Update::from_stamped(channel, stamped))
+                .collect(),
+
+            _ => Err(XTablesError::UnexpectedReply),
+        }
+    }
+
+    /// Channels the server knows whose name starts with `prefix`, sorted by name. An empty
+    /// prefix lists every channel.
+    pub fn list_channels(&self, prefix: &str) -> Result<Vec<ChannelInfo>, XTablesError> {
+        let payload = self.request(request::Payload::Channels(ListChannelsCommand {
+            prefix: prefix.to_string(),
+        }))?;
+        Self::channels_reply(payload)
+    }
+
+    pub(crate) fn channels_reply(
+        payload: reply::Payload,
+    ) -> Result<Vec<ChannelInfo>, XTablesError> {
+        match payload {
+            reply::Payload::Channels(command) => Ok(command
+                .channels
+                .into_iter()
+                .map(|info| ChannelInfo {
+                    channel: info.channel,
+                    value_type: info.value_type,
+                    last_update: info.last_update,
+                    update_count: info.update_count,
+                })
+                .collect()),
+
+            _ => Err(XTablesError::UnexpectedReply),
+        }
+    }
+
+    /// Removes `channel` and its values from the server. Subscribers keep their subscription
+    /// and receive values again if the channel is recreated.
+    pub fn delete_channel(&self, channel: &str) -> Result<(), XTablesError> {
+        topic::validate_channel(channel)?;
+        let payload = self.request(request::Payload::Delete(DeleteChannelCommand {
+            channel: channel.to_string(),
+        }))?;
+
+        match payload {
+            reply::Payload::Ack(_) => Ok(()),
+
+            _ => Err(XTablesError::UnexpectedReply),
+        }
+    }
+
+    /// Removes the values of `channel`, keeping the channel and its sequence numbers.
+    pub fn clear_channel(&self, channel: &str) -> Result<(), XTablesError> {
+        topic::validate_channel(channel)?;
+        let payload = self.request(request::Payload::Clear(ClearChannelCommand {
+            channel: channel.to_string(),
+        }))?;
 
-            _ => panic!("Unexpected reply payload type received"),
+        match payload {
+            reply::Payload::Ack(_) => Ok(()),
+
+            _ => Err(XTablesError::UnexpectedReply),
         }
     }
 
-    fn get_logs(&self) -> Vec<String> {
-        let req_socket = self.req_socket.clone();
+    /// Value types of the channels whose name starts with `prefix`, sorted by name. Channels
+    /// take the type of their first value unless one was declared, and the server rejects
+    /// values of any other type.
+    pub fn schemas(&self, prefix: &str) -> Result<Vec<ChannelSchema>, XTablesError> {
+        let payload = self.request(request::Payload::Schemas(GetSchemasCommand {
+            prefix: prefix.to_string(),
+        }))?;
 
-        let message = Self::request_log();
+        match payload {
+            reply::Payload::Schemas(command) => Ok(command
+                .schemas
+                .into_iter()
+                .map(|schema| ChannelSchema {
+                    channel: schema.channel,
+                    value_type: schema.value_type,
+                    declared: schema.declared,
+                })
+                .collect()),
 
-        req_socket.send(message, 0).unwrap();
-        let buffer = Cursor::new(req_socket.recv_bytes(0).unwrap());
-        let payload = Reply::decode(buffer).unwrap().payload.unwrap();
+            _ => Err(XTablesError::UnexpectedReply),
+        }
+    }
 
-        match &payload {
-            reply::Payload::Logs(command) => command.logs.clone(),
+    /// Fixes the value type of `channel`, e.g. `"double"`, before anything is written to it.
+    /// Fails if the channel already has another type. Requires the client to be built with
+    /// the server's admin token.
+    pub fn declare_type(&self, channel: &str, value_type: &str) -> Result<(), XTablesError> {
+        topic::validate_channel(channel)?;
+        let payload = self.request(request::Payload::Declare(DeclareTypeCommand {
+            channel: channel.to_string(),
+            value_type: value_type.to_string(),
+        }))?;
 
-            _ => panic!("Unexpected reply payload type received"),
+        match payload {
+            reply::Payload::Ack(_) => Ok(()),
+
+            _ => Err(XTablesError::UnexpectedReply),
         }
     }
 
-    pub fn subscribe<F>(&self, channel: &str, callback: F) -> impl FnOnce() + Send + 'static
+    /// Changes how many values the server keeps for the channel named `pattern`, or for every
+    /// channel starting with the prefix when `pattern` ends in `*`. Unlike in
+    /// `subscribe_pattern`, the prefix is matched as a string and covers every segment below
+    /// it. The most specific rule wins. Requires the client to be built with the server's
+    /// admin token.
+    pub fn set_retention(
+        &self,
+        pattern: &str,
+        policy: RetentionPolicy,
+    ) -> Result<(), XTablesError> {
+        let policy = match policy {
+            RetentionPolicy::Count(count) => retention_policy::Policy::Count(count),
+            RetentionPolicy::Window(window) => {
+                retention_policy::Policy::WindowMs(window.as_millis() as u64)
+            }
+            RetentionPolicy::Latest => retention_policy::Policy::Latest(true),
+        };
+        let payload = self.request(request::Payload::Retention(SetRetentionCommand {
+            pattern: pattern.to_string(),
+            policy: Some(protobuf::RetentionPolicy {
+                policy: Some(policy),
+            }),
+        }))?;
+
+        match payload {
+            reply::Payload::Ack(_) => Ok(()),
+
+            _ => Err(XTablesError::UnexpectedReply),
+        }
+    }
+
+    fn get_logs(&self) -> Result<Vec<String>, XTablesError> {
+        let payload = self.request(request::Payload::Logs(GetLogsCommand {}))?;
+
+        match payload {
+            reply::Payload::Logs(command) => Ok(command.logs),
+
+            _ => Err(XTablesError::UnexpectedReply),
+        }
+    }
+
+    /// Calls `callback` with the current value of `channel`, if it has one, and then with
+    /// every update of exactly that channel.
+    pub fn subscribe<F>(
+        &self,
+        channel: &str,
+        callback: F,
+    ) -> Result<impl FnOnce() + Send + 'static, XTablesError>
     where
-        F: Fn(&supported_values::Kind) + Send + 'static,
+        F: Fn(&Update) + Send + 'static,
     {
-        let sub_socket = self.sub_socket.clone();
-        sub_socket
-            .lock()
-            .unwrap()
-            .set_subscribe(channel.as_bytes())
-            .unwrap();
+        topic::validate_channel(channel)?;
+        self.subscribe_listener(ChannelPattern::parse(channel)?, callback)
+    }
 
-        let initial_value = self.get(channel);
-        if !(initial_value
-            == supported_values::Kind::String("XTABLES_INTERNAL_NO_DATA_AVAILABLE".to_string()))
-        {
-            callback(&initial_value);
+    /// Like `subscribe`, but hands `callback` the value as a `T`. Values of other types are
+    /// skipped.
+    pub fn subscribe_typed<T, F>(
+        &self,
+        channel: &str,
+        callback: F,
+    ) -> Result<impl FnOnce() + Send + 'static, XTablesError>
+    where
+        T: XTablesValue,
+        F: Fn(T) + Send + 'static,
+    {
+        self.subscribe(channel, move |update| {
+            if let Ok(value) = T::from_kind(update.value.clone()) {
+                callback(value);
+            }
+        })
+    }
+
+    /// Like `subscribe`, for every channel matching `pattern`. A `*` segment matches exactly
+    /// one segment and `**` any number of them, so `drive/*` receives `drive/left` but not
+    /// `drive/left/speed`, and `vision/**` receives `vision` and everything below it.
+    ///
+    /// The subscriber socket only filters by the segments before the first wildcard. A
+    /// pattern such as `*/speed` or `**` starts with a wildcard, so the client receives
+    /// every value the server publishes and decodes each one to filter it.
+    pub fn subscribe_pattern<F>(
+        &self,
+        pattern: &str,
+        callback: F,
+    ) -> Result<impl FnOnce() + Send + 'static, XTablesError>
+    where
+        F: Fn(&Update) + Send + 'static,
+    {
+        self.subscribe_listener(ChannelPattern::parse(pattern)?, callback)
+    }
+
+    fn subscribe_listener<F>(
+        &self,
+        pattern: ChannelPattern,
+        callback: F,
+    ) -> Result<impl FnOnce() + Send + 'static, XTablesError>
+    where
+        F: Fn(&Update) + Send + 'static,
+    {
+        // The listener is added before the current values are read, and updates arriving in
+        // the meantime are held back until the current values were passed on, so they stay in
+        // order. Like any zmq subscriber, the socket only receives updates once the server
+        // has seen the subscription, which is sent on in the background, so a value written
+        // right after a new pattern is subscribed can still be missed.
+        let listener = Arc::new(Mutex::new(NewListener {
+            callback,
+            held: Some(Vec::new()),
+        }));
+        let (key, subscribed) = {
+            let listener = Arc::clone(&listener);
+            let mut listeners = self.data_listeners.lock().unwrap();
+            Self::update_group(&mut listeners, &self.sub_socket, &pattern, |group| {
+                group.listeners.insert(Box::new(move |update: &Update| {
+                    let mut listener = listener.lock().unwrap();
+                    match listener.held.as_mut() {
+                        Some(held) => held.push(update.clone()),
+                        None => (listener.callback)(update),
+                    }
+                }))
+            })
+        };
+
+        let unsubscribe = {
+            let listeners = Arc::clone(&self.data_listeners);
+            let sub_socket = self.sub_socket.clone();
+            let pattern = pattern.clone();
+            move || {
+                let mut listeners = listeners.lock().unwrap();
+                // removing a listener only unsubscribes, which has nothing to report
+                let _ = Self::update_group(&mut listeners, &sub_socket, &pattern, |group| {
+                    group.listeners.remove(key);
+                });
+            }
+        };
+        if let Err(err) = subscribed {
+            unsubscribe();
+            return Err(err);
         }
 
-        let mut listeners = self.data_listeners.lock().unwrap();
-        let callback = Box::new(callback);
-        let key = listeners
-            .entry(channel.to_string())
-            .or_default()
-            .insert(Box::new(callback));
+        let current = match Self::current_values(&self.requests, &pattern, None) {
+            Ok(current) => current,
+            Err(err) => {
+                unsubscribe();
+                return Err(err);
+            }
+        };
+        let mut listener = listener.lock().unwrap();
+        let held = listener.held.take().unwrap_or_default();
+        current
+            .iter()
+            .chain(&Self::newer_updates(&current, held))
+            .for_each(&listener.callback);
+
+        Ok(unsubscribe)
+    }
+
+    /// The updates of `held` that are newer than the current values read alongside them.
+    fn newer_updates(current: &[Update], held: Vec<Update>) -> Vec<Update> {
+        let sequences: HashMap<&str, u64> = current
+            .iter()
+            .map(|update| (update.channel.as_str(), update.sequence))
+            .collect();
+        held.into_iter()
+            .filter(|update| {
+                sequences
+                    .get(update.channel.as_str())
+                    .is_none_or(|sequence| update.sequence > *sequence)
+            })
+            .collect()
+    }
+
+    /// Calls `callback` whenever a channel matching `pattern` is deleted or cleared. Patterns
+    /// follow the rules of `subscribe_pattern`, and a plain channel name matches only itself.
+    pub fn subscribe_removals<F>(
+        &self,
+        pattern: &str,
+        callback: F,
+    ) -> Result<impl FnOnce() + Send + 'static, XTablesError>
+    where
+        F: Fn(&Removal) + Send + 'static,
+    {
+        let pattern = ChannelPattern::parse(pattern)?;
+        let (key, subscribed) = {
+            let mut listeners = self.data_listeners.lock().unwrap();
+            Self::update_group(&mut listeners, &self.sub_socket, &pattern, |group| {
+                group.removal_listeners.insert(Box::new(callback))
+            })
+        };
 
         let listeners = Arc::clone(&self.data_listeners);
-        let channel = channel.to_string();
+        let sub_socket = self.sub_socket.clone();
+        let unsubscribe = move || {
+            let mut listeners = listeners.lock().unwrap();
+            let _ = Self::update_group(&mut listeners, &sub_socket, &pattern, |group| {
+                group.removal_listeners.remove(key);
+            });
+        };
+        if let Err(err) = subscribed {
+            unsubscribe();
+            return Err(err);
+        }
+        Ok(unsubscribe)
+    }
 
-        move || {
+    /// Calls `callback` with the values of every batch that touches a channel matching
+    /// `pattern`, limited to those channels. Listeners registered with `subscribe` still
+    /// receive the values one by one.
+    pub fn subscribe_batches<F>(
+        &self,
+        pattern: &str,
+        callback: F,
+    ) -> Result<impl FnOnce() + Send + 'static, XTablesError>
+    where
+        F: Fn(&[Update]) + Send + 'static,
+    {
+        let pattern = ChannelPattern::parse(pattern)?;
+        let (key, subscribed) = {
+            let mut listeners = self.data_listeners.lock().unwrap();
+            Self::update_group(&mut listeners, &self.sub_socket, &pattern, |group| {
+                group.batch_listeners.insert(Box::new(callback))
+            })
+        };
+
+        let listeners = Arc::clone(&self.data_listeners);
+        let sub_socket = self.sub_socket.clone();
+        let unsubscribe = move || {
             let mut listeners = listeners.lock().unwrap();
-            if let Some(slotmap) = listeners.get_mut(&channel) {
-                slotmap.remove(key);
-                if slotmap.is_empty() {
-                    listeners.remove(&channel);
-                    sub_socket
-                        .lock()
-                        .unwrap()
-                        .set_unsubscribe(channel.as_bytes())
-                        .unwrap();
-                }
-            }
+            let _ = Self::update_group(&mut listeners, &sub_socket, &pattern, |group| {
+                group.batch_listeners.remove(key);
+            });
+        };
+        if let Err(err) = subscribed {
+            unsubscribe();
+            return Err(err);
+        }
+        Ok(unsubscribe)
+    }
+
+    /// Updates of `channel` as a stream, starting with its current value, for async tasks
+    /// that `select!` over several sources. Buffers up to 64 updates, see
+    /// `watch_with_capacity`.
+    pub fn watch(&self, channel: &str) -> Result<UpdateStream, XTablesError> {
+        self.watch_with_capacity(channel, DEFAULT_WATCH_CAPACITY)
+    }
+
+    /// Like `watch`, buffering up to `capacity` updates. Once the buffer is full the oldest
+    /// update is dropped for each new one, which [`UpdateStream::take_lagged`] reports.
+    pub fn watch_with_capacity(
+        &self,
+        channel: &str,
+        capacity: usize,
+    ) -> Result<UpdateStream, XTablesError> {
+        let (sender, mut stream) = UpdateStream::channel(capacity);
+        let unsubscribe = self.subscribe(channel, move |update| sender.send(update))?;
+        stream.set_unsubscribe(Box::new(unsubscribe));
+        Ok(stream)
+    }
+
+    /// The latest value of `channel`, kept up to date in the background, for tasks that only
+    /// care about the newest value.
+    pub fn watch_latest(&self, channel: &str) -> Result<LatestValue, XTablesError> {
+        let (sender, mut latest) = LatestValue::channel();
+        let unsubscribe = self.subscribe(channel, move |update| {
+            sender.send_replace(Some(update.clone()));
+        })?;
+        latest.set_unsubscribe(Box::new(unsubscribe));
+        Ok(latest)
+    }
+
+    /// The latest values of the channels matching `pattern`, each request giving up after
+    /// `timeout`.
+    fn current_values(
+        requests: &Requests,
+        pattern: &ChannelPattern,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<Update>, XTablesError> {
+        let channels = if pattern.is_exact() {
+            vec![pattern.as_str().to_string()]
+        } else {
+            let payload = requests.request(
+                request::Payload::Channels(ListChannelsCommand {
+                    prefix: pattern.literal_prefix(),
+                }),
+                timeout,
+            )?;
+            Self::channels_reply(payload)?
+                .into_iter()
+                .filter(|info| info.value_type.is_some() && pattern.matches(&info.channel))
+                .map(|info| info.channel)
+                .collect()
+        };
+
+        let mut updates = Vec::with_capacity(channels.len());
+        for channel in channels {
+            let payload = requests.request(
+                request::Payload::Data(GetDataCommand {
+                    channel: channel.clone(),
+                }),
+                timeout,
+            )?;
+            updates.extend(Self::data_reply(&channel, payload)?);
         }
+        Ok(updates)
     }
 
-    pub fn subscribe_to_logs<F>(&self, callback: F) -> impl FnOnce() + Send + 'static
+    /// Changes the listeners of `pattern` through `change`, then subscribes the socket to the
+    /// topics the group needs from now on and unsubscribes it from those it no longer needs.
+    /// An empty group is dropped. Returns what `change` returned along with whether
+    /// subscribing worked.
+    fn update_group<R>(
+        listeners: &mut HashMap<String, PatternListeners>,
+        sub_socket: &Mutex<zmq::Socket>,
+        pattern: &ChannelPattern,
+        change: impl FnOnce(&mut PatternListeners) -> R,
+    ) -> (R, Result<(), XTablesError>) {
+        let group = listeners
+            .entry(pattern.as_str().to_string())
+            .or_insert_with(|| PatternListeners {
+                pattern: pattern.clone(),
+                listeners: SlotMap::new(),
+                removal_listeners: SlotMap::new(),
+                batch_listeners: SlotMap::new(),
+            });
+        let before = group.topics();
+        let result = change(group);
+        let after = group.topics();
+        if after.is_empty() {
+            listeners.remove(pattern.as_str());
+        }
+
+        let sub_socket = sub_socket.lock().unwrap();
+        for topic in before.iter().filter(|topic| !after.contains(topic)) {
+            // if this fails the topic keeps arriving but has no listeners to call
+            let _ = sub_socket.set_unsubscribe(topic.as_bytes());
+        }
+        let subscribed = after
+            .iter()
+            .filter(|topic| !before.contains(topic))
+            .try_for_each(|topic| sub_socket.set_subscribe(topic.as_bytes()))
+            .map_err(XTablesError::from);
+        (result, subscribed)
+    }
+
+    pub fn subscribe_to_logs<F>(
+        &self,
+        callback: F,
+    ) -> Result<impl FnOnce() + Send + 'static, XTablesError>
     where
         F: Fn(&String) + Send + 'static,
     {
@@ -246,10 +1099,9 @@ impl XTablesClient {
         sub_socket
             .lock()
             .unwrap()
-            .set_subscribe("XTABLES_INTERNAL_LOG".as_bytes())
-            .unwrap();
+            .set_subscribe(topic::LOG_TOPIC.as_bytes())?;
 
-        let initial_value = self.get_logs();
+        let initial_value = self.get_logs()?;
 
         initial_value.iter().for_each(|log| {
             callback(log);
@@ -262,73 +1114,303 @@ impl XTablesClient {
 
         let listeners = Arc::clone(&self.log_listeners);
 
-        move || {
+        Ok(move || {
             listeners.lock().unwrap().remove(key);
             if listeners.lock().unwrap().is_empty() {
-                sub_socket
+                let _ = sub_socket
                     .lock()
                     .unwrap()
-                    .set_unsubscribe("XTABLES_INTERNAL_LOG".as_bytes())
-                    .unwrap();
+                    .set_unsubscribe(topic::LOG_TOPIC.as_bytes());
             }
+        })
+    }
+
+    /// Calls `callback` whenever the client loses or regains the server, which it notices by
+    /// the heartbeats the server publishes. Requires the receiver thread to run, see `start`.
+    ///
+    /// After the server comes back the client resubscribes its listeners and passes them the
+    /// current values again before calling `callback` with [`ConnectionState::Connected`].
+    pub fn on_connection_change<F>(&self, callback: F) -> impl FnOnce() + Send + 'static
+    where
+        F: Fn(ConnectionState) + Send + 'static,
+    {
+        let key = self
+            .connection_listeners
+            .lock()
+            .unwrap()
+            .insert(Box::new(callback));
+        let listeners = Arc::clone(&self.connection_listeners);
+        move || {
+            listeners.lock().unwrap().remove(key);
         }
     }
 
+    /// Spawns the thread that receives publishes and calls the subscribed listeners. The
+    /// thread also watches the heartbeats of the server and reconnects when it was lost.
     pub fn start(&self) {
-        if !self.initialized.load(Ordering::SeqCst) {
-            self.initialized.store(true, Ordering::SeqCst);
-        } else if self.stop.load(Ordering::SeqCst) {
-            self.stop.store(false, Ordering::SeqCst);
-        } else {
+        let mut receiver = self.receiver.lock().unwrap();
+        if receiver.is_some() {
             return;
         }
+        self.stop.store(false, Ordering::SeqCst);
         {
             let sub_socket = self.sub_socket.clone();
             let data_listeners = self.data_listeners.clone();
             let log_listeners = self.log_listeners.clone();
             let stop: Arc<AtomicBool> = self.stop.clone();
+            let mut recovery = Recovery {
+                endpoints: self.endpoints.clone(),
+                requests: self.requests.clone(),
+                sub_socket: self.sub_socket.clone(),
+                data_listeners: self.data_listeners.clone(),
+                log_listeners: self.log_listeners.clone(),
+                connection_listeners: self.connection_listeners.clone(),
+                timeout: RECOVERY_TIMEOUT,
+                refresh: None,
+            };
+            let mut heartbeats = HeartbeatMonitor::new();
 
-            task::spawn_blocking(move || {
-                let sub_socket = sub_socket.lock().unwrap();
-                loop {
-                    if stop.load(Ordering::SeqCst) {
-                        break;
-                    }
-                    let topic = sub_socket.recv_string(0).unwrap().unwrap();
-                    let bytes = sub_socket.recv_bytes(0).unwrap();
-                    let data = Publish::decode(Cursor::new(bytes)).unwrap();
-                    let payload = &data.payload.unwrap();
-
-                    match payload {
-                        publish::Payload::Data(command) => {
-                            let mut listeners = data_listeners.lock().unwrap();
-                            let data = command.value.clone().unwrap().kind.unwrap();
-
-                            listeners
-                                .entry(topic)
-                                .or_default()
-                                .iter()
-                                .for_each(|(_, callback)| {
-                                    callback(&data);
-                                });
+            let handle = thread::Builder::new()
+                .name("xtables-client-sub".to_string())
+                .spawn(move || {
+                    loop {
+                        if stop.load(Ordering::SeqCst) {
+                            break;
                         }
-                        publish::Payload::Logs(command) => {
-                            let listeners = log_listeners.lock().unwrap();
+                        // the socket is only held while reading so subscribe can update it meanwhile
+                        let frames = {
+                            let sub_socket = sub_socket.lock().unwrap();
+                            match sub_socket.poll(zmq::POLLIN, SUB_POLL_TIMEOUT_MS) {
+                                Ok(ready) if ready > 0 => Some(sub_socket.recv_multipart(0)),
+                                _ => None,
+                            }
+                        };
+                        if let Some(event) = heartbeats.check(Instant::now()) {
+                            recovery.handle(event);
+                        }
+                        recovery.refresh(Instant::now());
+                        let Some(frames) = frames else {
+                            continue;
+                        };
+                        // malformed frames are skipped, one bad publish must not end the loop
+                        let Ok(frames) = frames else {
+                            continue;
+                        };
+                        // the topic frame only serves the socket's filtering
+                        let Ok([_, bytes]) = <[Vec<u8>; 2]>::try_from(frames) else {
+                            continue;
+                        };
+                        let Some(payload) = Publish::decode(Cursor::new(bytes))
+                            .ok()
+                            .and_then(|data| data.payload)
+                        else {
+                            continue;
+                        };
+
+                        match &payload {
+                            publish::Payload::Data(command) => {
+                                let listeners = data_listeners.lock().unwrap();
+                                let Some(data) = Update::from_publish(command) else {
+                                    continue;
+                                };
 
-                            command.logs.iter().for_each(|log| {
-                                listeners.iter().for_each(|(_, callback)| {
-                                    callback(log);
+                                listeners
+                                    .values()
+                                    .filter(|group| group.pattern.matches(&data.channel))
+                                    .flat_map(|group| group.listeners.values())
+                                    .for_each(|callback| callback(&data));
+                            }
+                            publish::Payload::Batch(command) => {
+                                let listeners = data_listeners.lock().unwrap();
+                                let updates: Vec<Update> = command
+                                    .values
+                                    .iter()
+                                    .filter_map(Update::from_publish)
+                                    .collect();
+
+                                // the values were also published one by one, which is how
+                                // plain listeners receive them
+                                for group in listeners.values() {
+                                    let matching: Vec<Update> = updates
+                                        .iter()
+                                        .filter(|update| group.pattern.matches(&update.channel))
+                                        .cloned()
+                                        .collect();
+                                    if matching.is_empty() {
+                                        continue;
+                                    }
+                                    group
+                                        .batch_listeners
+                                        .values()
+                                        .for_each(|callback| callback(&matching));
+                                }
+                            }
+                            publish::Payload::Removal(command) => {
+                                let listeners = data_listeners.lock().unwrap();
+                                let removal = Removal {
+                                    channel: command.channel.clone(),
+                                    deleted: command.deleted,
+                                };
+
+                                listeners
+                                    .values()
+                                    .filter(|group| group.pattern.matches(&removal.channel))
+                                    .flat_map(|group| group.removal_listeners.values())
+                                    .for_each(|callback| callback(&removal));
+                            }
+                            publish::Payload::Logs(command) => {
+                                let listeners = log_listeners.lock().unwrap();
+
+                                command.logs.iter().for_each(|log| {
+                                    listeners.iter().for_each(|(_, callback)| {
+                                        callback(log);
+                                    });
                                 });
-                            });
+                            }
+                            publish::Payload::Heartbeat(command) => {
+                                let interval = Duration::from_millis(command.interval_ms as u64);
+                                if let Some(event) =
+                                    heartbeats.heartbeat(command.instance, interval, Instant::now())
+                                {
+                                    recovery.handle(event);
+                                }
+                            }
                         }
                     }
-                }
-            });
+                })
+                .expect("Failed to spawn XTables receiver thread");
+            *receiver = Some(handle);
         }
     }
 
+    /// Signals the receiver thread and waits for it to exit. `start` may be called again later.
     pub fn stop(&self) {
+        let Some(receiver) = self.receiver.lock().unwrap().take() else {
+            return;
+        };
         self.stop.store(true, Ordering::SeqCst);
+        let _ = receiver.join();
+    }
+}
+
+/// What the receiver thread needs to recover the connection after losing the server.
+struct Recovery {
+    endpoints: Arc<Endpoints>,
+    requests: Arc<Requests>,
+    sub_socket: Arc<Mutex<zmq::Socket>>,
+    data_listeners: SubscribeListenerMap,
+    log_listeners: LogListenerMap,
+    connection_listeners: ConnectionListenerMap,
+    /// Bounds the requests of the receiver thread. Only that thread notices a lost server and
+    /// fails the requests waiting on it, so it must never wait for a reply for long, whether
+    /// or not the server ever sent a heartbeat.
+    timeout: Duration,
+    /// Set while the server is back but listeners still wait for its current values: the
+    /// patterns left to refresh and when to try next.
+    refresh: Option<(Vec<ChannelPattern>, Instant)>,
+}
+
+impl Recovery {
+    fn handle(&mut self, event: HeartbeatEvent) {
+        match event {
+            HeartbeatEvent::Connected => self.notify(ConnectionState::Connected),
+            HeartbeatEvent::Lost => {
+                // listeners were not told the server was back while its values were pending
+                if self.refresh.take().is_none() {
+                    self.notify(ConnectionState::Disconnected);
+                }
+                // if this fails the sockets are replaced when the server is lost the next time
+                let _ = self.reset_sockets();
+            }
+            HeartbeatEvent::Restored => {
+                let patterns = {
+                    let listeners = self.data_listeners.lock().unwrap();
+                    listeners
+                        .values()
+                        .map(|group| group.pattern.clone())
+                        .collect()
+                };
+                let now = Instant::now();
+                self.refresh = Some((patterns, now));
+                self.refresh(now);
+            }
+        }
+    }
+
+    fn notify(&self, state: ConnectionState) {
+        let listeners = self.connection_listeners.lock().unwrap();
+        listeners.values().for_each(|callback| callback(state));
+    }
+
+    /// Replaces the request socket, whose replies were lost with the server, and the
+    /// subscriber socket, subscribed again to the topics of the current listeners. The
+    /// heartbeat topic is subscribed last, so once a heartbeat arrives on the new socket the
+    /// server has every other subscription too.
+    fn reset_sockets(&self) -> Result<(), XTablesError> {
+        self.requests.reset(self.endpoints.dealer()?);
+
+        let listeners = self.data_listeners.lock().unwrap();
+        let log_listeners = self.log_listeners.lock().unwrap();
+        let socket = self.endpoints.sub()?;
+        for group in listeners.values() {
+            for topic in group.topics() {
+                socket.set_subscribe(topic.as_bytes())?;
+            }
+        }
+        if !log_listeners.is_empty() {
+            socket.set_subscribe(topic::LOG_TOPIC.as_bytes())?;
+        }
+        socket.set_subscribe(topic::HEARTBEAT_TOPIC.as_bytes())?;
+        *self.sub_socket.lock().unwrap() = socket;
+        Ok(())
+    }
+
+    /// Passes every listener the current values of its channels, which may have changed
+    /// while the server was unreachable, and reports the connection once all of them got
+    /// theirs. When the server does not answer, the patterns left are tried again after
+    /// [`REFRESH_RETRY_INTERVAL`].
+    fn refresh(&mut self, now: Instant) {
+        let Some((patterns, retry_at)) = &mut self.refresh else {
+            return;
+        };
+        if now < *retry_at {
+            return;
+        }
+        while let Some(pattern) = patterns.last() {
+            match XTablesClient::current_values(&self.requests, pattern, Some(self.timeout)) {
+                Ok(updates) => {
+                    let listeners = self.data_listeners.lock().unwrap();
+                    if let Some(group) = listeners.get(pattern.as_str()) {
+                        for update in &updates {
+                            group
+                                .listeners
+                                .values()
+                                .for_each(|callback| callback(update));
+                        }
+                    }
+                }
+                // the server is gone again, or the request went out just before its socket was
+                // replaced
+                Err(XTablesError::Timeout | XTablesError::ConnectionLost) => {
+                    *retry_at = Instant::now() + REFRESH_RETRY_INTERVAL;
+                    return;
+                }
+                // the server cannot serve this pattern, trying again would not help
+                Err(_) => {}
+            }
+            patterns.pop();
+        }
+        self.refresh = None;
+        self.notify(ConnectionState::Connected);
+    }
+}
+
+impl Drop for XTablesClient {
+    fn drop(&mut self) {
+        self.stop();
+        // streams and watches keep their listeners alive through their unsubscribe handles,
+        // dropping the listeners here ends them
+        self.data_listeners.lock().unwrap().clear();
     }
 }
 
@@ -337,3 +1419,374 @@ impl Default for XTablesClient {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::pin::Pin;
+
+    use futures_core::Stream;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn clients_are_thread_safe() {
+        assert_send_sync::<XTablesClient>();
+        assert_send_sync::<Arc<XTablesClient>>();
+        assert_send_sync::<AsyncXTablesClient>();
+    }
+
+    fn data(data: reply_data_command::Data) -> reply::Payload {
+        reply::Payload::Data(protobuf::ReplyDataCommand {
+            data: Some(data),
+            source_timestamp: None,
+            server_timestamp: 20,
+            sequence: 2,
+        })
+    }
+
+    fn string(value: &str) -> reply_data_command::Data {
+        reply_data_command::Data::Value(SupportedValues {
+            kind: Some(supported_values::Kind::String(value.to_string())),
+        })
+    }
+
+    #[test]
+    fn upgrades_legacy_replies() {
+        let not_found = data(reply_data_command::Data::NotFound(NotFound {}));
+        assert_eq!(
+            XTablesClient::upgrade_reply(1, data(string(LEGACY_NO_DATA))),
+            not_found
+        );
+        // only servers that predate `not_found` use the sentinel
+        assert_eq!(
+            XTablesClient::upgrade_reply(PROTOCOL_VERSION, data(string(LEGACY_NO_DATA))),
+            data(string(LEGACY_NO_DATA))
+        );
+        assert_eq!(
+            XTablesClient::upgrade_reply(1, data(string("ready"))),
+            data(string("ready"))
+        );
+    }
+
+    #[test]
+    fn data_replies() {
+        let update = XTablesClient::data_reply("auto/mode", data(string("ready")))
+            .unwrap()
+            .unwrap();
+        assert_eq!(update.channel, "auto/mode");
+        assert_eq!(
+            update.value,
+            supported_values::Kind::String(String::from("ready"))
+        );
+        assert_eq!((update.sequence, update.server_timestamp), (2, 20));
+
+        let not_found = data(reply_data_command::Data::NotFound(NotFound {}));
+        assert!(matches!(
+            XTablesClient::data_reply("auto/mode", not_found),
+            Ok(None)
+        ));
+        assert!(matches!(
+            XTablesClient::data_reply("auto/mode", reply::Payload::Channels(Default::default())),
+            Err(XTablesError::UnexpectedReply)
+        ));
+    }
+
+    #[test]
+    fn channels_replies() {
+        let reply = reply::Payload::Channels(protobuf::ReplyChannelsCommand {
+            channels: vec![
+                protobuf::ChannelInfo {
+                    channel: String::from("drive/left"),
+                    value_type: Some(String::from("double")),
+                    last_update: Some(20),
+                    update_count: 2,
+                },
+                protobuf::ChannelInfo {
+                    channel: String::from("drive/right"),
+                    value_type: None,
+                    last_update: None,
+                    update_count: 1,
+                },
+            ],
+        });
+
+        let channels = XTablesClient::channels_reply(reply).unwrap();
+        assert_eq!(
+            channels,
+            [
+                ChannelInfo {
+                    channel: String::from("drive/left"),
+                    value_type: Some(String::from("double")),
+                    last_update: Some(20),
+                    update_count: 2,
+                },
+                ChannelInfo {
+                    channel: String::from("drive/right"),
+                    value_type: None,
+                    last_update: None,
+                    update_count: 1,
+                },
+            ]
+        );
+        assert!(matches!(
+            XTablesClient::channels_reply(data(string("ready"))),
+            Err(XTablesError::UnexpectedReply)
+        ));
+    }
+
+    #[test]
+    fn skips_held_updates_older_than_current_values() {
+        let update = |channel: &str, sequence| Update {
+            channel: channel.to_string(),
+            value: supported_values::Kind::Int32(sequence as i32),
+            sequence,
+            server_timestamp: 0,
+            source_timestamp: None,
+        };
+        let current = [update("drive/left", 3), update("drive/right", 1)];
+        let held = vec![
+            update("drive/left", 2),
+            update("drive/left", 3),
+            update("drive/right", 2),
+            update("drive/left", 4),
+            update("drive/rear", 1),
+        ];
+
+        let newer: Vec<(String, u64)> = XTablesClient::newer_updates(&current, held)
+            .into_iter()
+            .map(|update| (update.channel, update.sequence))
+            .collect();
+        assert_eq!(
+            newer,
+            [
+                (String::from("drive/right"), 2),
+                (String::from("drive/left"), 4),
+                (String::from("drive/rear"), 1),
+            ]
+        );
+    }
+
+    /// Answers `count` requests on `port` with `not_found`, standing in for a server. Returns
+    /// the socket, since closing it drops replies the client has not read yet.
+    fn answer_not_found(port: u16, count: usize) -> JoinHandle<zmq::Socket> {
+        let context = Context::new();
+        let router = context.socket(zmq::ROUTER).unwrap();
+        // fails the test instead of hanging it when a request never arrives
+        router.set_rcvtimeo(5000).unwrap();
+        router.bind(&format!("tcp://127.0.0.1:{}", port)).unwrap();
+        thread::spawn(move || {
+            for _ in 0..count {
+                let frames = router.recv_multipart(0).unwrap();
+                let request = Request::decode(&frames[1][..]).unwrap();
+                let reply = Reply {
+                    id: request.id,
+                    protocol_version: PROTOCOL_VERSION,
+                    payload: Some(data(reply_data_command::Data::NotFound(NotFound {}))),
+                };
+                router.send(&frames[0], zmq::SNDMORE).unwrap();
+                router.send(reply.encode_to_vec(), 0).unwrap();
+            }
+            router
+        })
+    }
+
+    #[tokio::test]
+    async fn watches_end_with_the_client() {
+        let ports = XTablesPorts {
+            push: 49661,
+            req: 49662,
+            sub: 49663,
+        };
+        let server = answer_not_found(ports.req, 2);
+        let client = XTablesClient::connect("127.0.0.1", ports).unwrap();
+        let mut stream = client.watch("drive/speed").unwrap();
+        let mut latest = client.watch_latest("drive/speed").unwrap();
+        let _server = server.join().unwrap();
+
+        drop(client);
+        let timeout = Duration::from_secs(5);
+        let next = std::future::poll_fn(|cx| Pin::new(&mut stream).poll_next(cx));
+        assert!(tokio::time::timeout(timeout, next).await.unwrap().is_none());
+        assert!(
+            tokio::time::timeout(timeout, latest.changed())
+                .await
+                .unwrap()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn refresh_is_retried_until_the_server_answers() {
+        let ports = XTablesPorts {
+            push: 49671,
+            req: 49672,
+            sub: 49673,
+        };
+        let endpoints = Endpoints::new(Context::new(), "127.0.0.1", &ports);
+        let sub_socket = Arc::new(Mutex::new(endpoints.sub().unwrap()));
+        let data_listeners: SubscribeListenerMap = Arc::new(Mutex::new(HashMap::new()));
+        let pattern = ChannelPattern::parse("drive/speed").unwrap();
+        XTablesClient::update_group(
+            &mut data_listeners.lock().unwrap(),
+            &sub_socket,
+            &pattern,
+            |group| group.listeners.insert(Box::new(|_: &Update| {})),
+        )
+        .1
+        .unwrap();
+        let states = Arc::new(Mutex::new(Vec::new()));
+        let connection_listeners: ConnectionListenerMap = Arc::new(Mutex::new(SlotMap::new()));
+        {
+            let states = Arc::clone(&states);
+            connection_listeners
+                .lock()
+                .unwrap()
+                .insert(Box::new(move |state| states.lock().unwrap().push(state)));
+        }
+        let mut recovery = Recovery {
+            requests: Arc::new(endpoints.requests(String::new()).unwrap()),
+            endpoints: Arc::new(endpoints),
+            sub_socket,
+            data_listeners,
+            log_listeners: Arc::new(Mutex::new(SlotMap::new())),
+            connection_listeners,
+            timeout: Duration::from_millis(200),
+            refresh: None,
+        };
+
+        let restored = Instant::now();
+        recovery.handle(HeartbeatEvent::Restored);
+        assert!(restored.elapsed() < Duration::from_secs(5));
+        // the server only counts as back once its current values arrived
+        assert!(states.lock().unwrap().is_empty());
+        recovery.handle(HeartbeatEvent::Lost);
+        assert!(states.lock().unwrap().is_empty());
+        recovery.handle(HeartbeatEvent::Restored);
+
+        let server = answer_not_found(ports.req, 1);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while states.lock().unwrap().is_empty() && Instant::now() < deadline {
+            recovery.refresh(Instant::now());
+            thread::sleep(Duration::from_millis(50));
+        }
+        let _server = server.join().unwrap();
+        assert_eq!(*states.lock().unwrap(), [ConnectionState::Connected]);
+        assert!(recovery.refresh.is_none());
+    }
+
+    #[test]
+    fn groups_subscribe_only_what_they_listen_for() {
+        let context = Context::new();
+        let sub_socket = Mutex::new(context.socket(zmq::SUB).unwrap());
+        let mut listeners = HashMap::new();
+        let pattern = ChannelPattern::parse("vision/**").unwrap();
+        let topics = |listeners: &HashMap<String, PatternListeners>| {
+            listeners
+                .get("vision/**")
+                .map(PatternListeners::topics)
+                .unwrap_or_default()
+        };
+
+        let (removal, subscribed) =
+            XTablesClient::update_group(&mut listeners, &sub_socket, &pattern, |group| {
+                group.removal_listeners.insert(Box::new(|_: &Removal| {}))
+            });
+        subscribed.unwrap();
+        assert_eq!(topics(&listeners), [topic::REMOVAL_TOPIC]);
+
+        let (update, subscribed) =
+            XTablesClient::update_group(&mut listeners, &sub_socket, &pattern, |group| {
+                group.listeners.insert(Box::new(|_: &Update| {}))
+            });
+        subscribed.unwrap();
+        assert_eq!(topics(&listeners), ["vision", topic::REMOVAL_TOPIC]);
+
+        let _ = XTablesClient::update_group(&mut listeners, &sub_socket, &pattern, |group| {
+            group.removal_listeners.remove(removal)
+        });
+        assert_eq!(topics(&listeners), ["vision"]);
+
+        let _ = XTablesClient::update_group(&mut listeners, &sub_socket, &pattern, |group| {
+            group.listeners.remove(update)
+        });
+        assert!(listeners.is_empty());
+    }
+
+    #[test]
+    fn acked_writes_time_out_without_server() {
+        let ports = XTablesPorts {
+            push: 49691,
+            req: 49692,
+            sub: 49693,
+        };
+        let client = XTablesClient::builder()
+            .host("127.0.0.1")
+            .ports(ports)
+            .ack_timeout(Duration::from_millis(300))
+            .connect()
+            .unwrap();
+
+        let started = Instant::now();
+        assert!(matches!(
+            client.set_acked("auto/step", 3i64),
+            Err(XTablesError::Timeout)
+        ));
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        // the timed out value was never queued, so a server showing up later only receives
+        // the requests sent once it is there
+        let context = Context::new();
+        let router = context.socket(zmq::ROUTER).unwrap();
+        router.set_rcvtimeo(2000).unwrap();
+        router.bind("tcp://127.0.0.1:49692").unwrap();
+        let frames = thread::scope(|scope| {
+            scope.spawn(|| client.set_acked("auto/step", 4i64));
+            router.recv_multipart(0).unwrap()
+        });
+        let request = Request::decode(&frames[1][..]).unwrap();
+        let Some(request::Payload::Set(command)) = request.payload else {
+            panic!("expected a set request");
+        };
+        assert_eq!(
+            command.value.unwrap().kind,
+            Some(supported_values::Kind::Int64(4))
+        );
+    }
+
+    #[test]
+    fn removals_check_channel_names() {
+        let ports = XTablesPorts {
+            push: 49681,
+            req: 49682,
+            sub: 49683,
+        };
+        let client = XTablesClient::connect("127.0.0.1", ports).unwrap();
+
+        // rejected before any request is sent, there is no server to answer one
+        assert!(matches!(
+            client.delete_channel("drive/*"),
+            Err(XTablesError::InvalidChannel(_))
+        ));
+        assert!(matches!(
+            client.clear_channel("drive/"),
+            Err(XTablesError::InvalidChannel(_))
+        ));
+    }
+
+    #[test]
+    fn drop_returns_without_server() {
+        let ports = XTablesPorts {
+            push: 49601,
+            req: 49602,
+            sub: 49603,
+        };
+        let client = XTablesClient::connect("127.0.0.1", ports).unwrap();
+        client.send_i32("drive/speed", 1).unwrap();
+
+        let dropped = Instant::now();
+        drop(client);
+        assert!(dropped.elapsed() < Duration::from_millis(PUSH_LINGER_MS as u64 * 4));
+    }
+}
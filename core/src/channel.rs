@@ -0,0 +1,353 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use xtables_protobuf::protobuf::{StampedValue, SupportedValues, supported_values};
+
+use crate::{error::XTablesError, retention::RetentionPolicy, utils::ring_buffer::RingBuffer};
+
+/// A value as the server stored it, stamped on arrival.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredValue {
+    pub kind: supported_values::Kind,
+    /// Per channel counter, the first value pushed to a channel is 1.
+    pub sequence: u64,
+    /// Microseconds since the unix epoch at which the server received the value.
+    pub server_timestamp: u64,
+    /// Microseconds since the unix epoch at which the sender produced the value, if it said.
+    pub source_timestamp: Option<u64>,
+}
+
+impl StoredValue {
+    pub fn to_stamped(&self) -> StampedValue {
+        StampedValue {
+            value: Some(SupportedValues {
+                kind: Some(self.kind.clone()),
+            }),
+            source_timestamp: self.source_timestamp,
+            server_timestamp: self.server_timestamp,
+            sequence: self.sequence,
+        }
+    }
+
+    /// Returns `None` if `stamped` carries no value.
+    pub fn from_stamped(stamped: StampedValue) -> Option<Self> {
+        Some(StoredValue {
+            kind: stamped.value?.kind?,
+            sequence: stamped.sequence,
+            server_timestamp: stamped.server_timestamp,
+            source_timestamp: stamped.source_timestamp,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HistoryQuery {
+    /// The most recent values, at most this many.
+    Last(usize),
+    /// Values whose sequence number is greater than this one.
+    SinceSequence(u64),
+    /// Values received at or after this many microseconds since the unix epoch.
+    SinceTimestamp(u64),
+}
+
+pub struct Channel {
+    pub values: RingBuffer<StoredValue>,
+    policy: RetentionPolicy,
+    last_sequence: u64,
+}
+
+impl Channel {
+    pub fn new(policy: RetentionPolicy) -> Self {
+        // window policies only reserve room as values arrive, not their whole upper bound
+        let mut channel = Channel {
+            values: RingBuffer::new(0),
+            policy,
+            last_sequence: 0,
+        };
+        channel.set_policy(policy);
+        channel
+    }
+
+    pub fn policy(&self) -> RetentionPolicy {
+        self.policy
+    }
+
+    /// Switches to `policy`, dropping the values it no longer keeps.
+    pub fn set_policy(&mut self, policy: RetentionPolicy) {
+        self.policy = policy;
+        self.values.set_capacity(policy.capacity());
+        self.expire(now_micros());
+    }
+
+    /// Drops values older than the retention window, keeping the latest one.
+    fn expire(&mut self, now: u64) {
+        let Some(window) = self.policy.window() else {
+            return;
+        };
+        let cutoff = now.saturating_sub(window.as_micros() as u64);
+        while self.values.items.len() > 1
+            && self
+                .values
+                .items
+                .front()
+                .is_some_and(|value| value.server_timestamp < cutoff)
+        {
+            self.values.items.pop_front();
+        }
+    }
+
+    /// Stamps `kind` with the next sequence number and the current time, then stores it.
+    pub fn push(
+        &mut self,
+        kind: supported_values::Kind,
+        source_timestamp: Option<u64>,
+    ) -> &StoredValue {
+        self.push_at(kind, source_timestamp, now_micros())
+    }
+
+    /// Like `push`, with the server timestamp given, so values written together share it.
+    pub fn push_at(
+        &mut self,
+        kind: supported_values::Kind,
+        source_timestamp: Option<u64>,
+        server_timestamp: u64,
+    ) -> &StoredValue {
+        self.last_sequence += 1;
+        self.values.push(StoredValue {
+            kind,
+            sequence: self.last_sequence,
+            server_timestamp,
+            source_timestamp,
+        });
+        self.expire(server_timestamp);
+        self.values.peek().unwrap()
+    }
+
+    /// Stores a value recovered from disk with its original stamps. Values at or below the
+    /// current sequence number were already restored and are skipped.
+    pub fn restore(&mut self, value: StoredValue) {
+        if value.sequence <= self.last_sequence {
+            return;
+        }
+        self.last_sequence = value.sequence;
+        self.values.push(value);
+        self.expire(now_micros());
+    }
+
+    /// Drops every value, the next push still gets the next sequence number.
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+
+    /// Values pushed to the channel so far, including ones that have since expired.
+    pub fn update_count(&self) -> u64 {
+        self.last_sequence
+    }
+
+    pub fn latest(&self) -> Option<&StoredValue> {
+        self.values.peek()
+    }
+
+    /// Values matching `query` that are still buffered, oldest first.
+    pub fn history(&mut self, query: HistoryQuery) -> Vec<&StoredValue> {
+        self.expire(now_micros());
+        let items = &self.values.items;
+        match query {
+            HistoryQuery::Last(count) => items
+                .iter()
+                .skip(items.len().saturating_sub(count))
+                .collect(),
+            HistoryQuery::SinceSequence(sequence) => items
+                .iter()
+                .filter(|value| value.sequence > sequence)
+                .collect(),
+            HistoryQuery::SinceTimestamp(timestamp) => items
+                .iter()
+                .filter(|value| value.server_timestamp >= timestamp)
+                .collect(),
+        }
+    }
+}
+
+/// `current` plus `delta`, where an empty channel counts as zero. Both must be the same
+/// numeric type, integers fail rather than overflow.
+pub fn add(
+    current: Option<&supported_values::Kind>,
+    delta: supported_values::Kind,
+) -> Result<supported_values::Kind, XTablesError> {
+    use supported_values::Kind;
+
+    let overflow = || XTablesError::InvalidOperation(String::from("addition overflows"));
+    let Some(current) = current else {
+        return match delta {
+            Kind::Int32(_)
+            | Kind::Int64(_)
+            | Kind::Uint32(_)
+            | Kind::Uint64(_)
+            | Kind::Double(_)
+            | Kind::Float(_) => Ok(delta),
+            delta => Err(XTablesError::InvalidOperation(format!(
+                "cannot add {}",
+                delta.type_name()
+            ))),
+        };
+    };
+    match (current, delta) {
+        (Kind::Int32(a), Kind::Int32(b)) => a.checked_add(b).map(Kind::Int32).ok_or_else(overflow),
+        (Kind::Int64(a), Kind::Int64(b)) => a.checked_add(b).map(Kind::Int64).ok_or_else(overflow),
+        (Kind::Uint32(a), Kind::Uint32(b)) => {
+            a.checked_add(b).map(Kind::Uint32).ok_or_else(overflow)
+        }
+        (Kind::Uint64(a), Kind::Uint64(b)) => {
+            a.checked_add(b).map(Kind::Uint64).ok_or_else(overflow)
+        }
+        (Kind::Double(a), Kind::Double(b)) => Ok(Kind::Double(a + b)),
+        (Kind::Float(a), Kind::Float(b)) => Ok(Kind::Float(a + b)),
+        (current, delta) => Err(XTablesError::InvalidOperation(format!(
+            "cannot add {} to {}",
+            delta.type_name(),
+            current.type_name()
+        ))),
+    }
+}
+
+/// The negation of a bool value, where an empty channel counts as false.
+pub fn toggle(
+    current: Option<&supported_values::Kind>,
+) -> Result<supported_values::Kind, XTablesError> {
+    match current {
+        None => Ok(supported_values::Kind::Bool(true)),
+        Some(supported_values::Kind::Bool(value)) => Ok(supported_values::Kind::Bool(!value)),
+        Some(current) => Err(XTablesError::InvalidOperation(format!(
+            "cannot toggle {}",
+            current.type_name()
+        ))),
+    }
+}
+
+pub fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequence() {
+        let mut channel = Channel::new(RetentionPolicy::Count(2));
+
+        let first = channel
+            .push(supported_values::Kind::Int32(1), None)
+            .sequence;
+        let second = channel
+            .push(supported_values::Kind::Int32(2), None)
+            .sequence;
+        let third = channel
+            .push(supported_values::Kind::Int32(3), Some(7))
+            .sequence;
+        assert_eq!((first, second, third), (1, 2, 3));
+
+        let latest = channel.latest().unwrap();
+        assert_eq!(latest.kind, supported_values::Kind::Int32(3));
+        assert_eq!(latest.source_timestamp, Some(7));
+        assert!(latest.server_timestamp > 0);
+    }
+
+    #[test]
+    fn history() {
+        let mut channel = Channel::new(RetentionPolicy::Count(3));
+        for value in 1..=4 {
+            channel.push(supported_values::Kind::Int32(value), None);
+        }
+        let sequences =
+            |values: Vec<&StoredValue>| values.iter().map(|v| v.sequence).collect::<Vec<_>>();
+
+        assert_eq!(sequences(channel.history(HistoryQuery::Last(2))), [3, 4]);
+        assert_eq!(
+            sequences(channel.history(HistoryQuery::Last(10))),
+            [2, 3, 4]
+        );
+        assert_eq!(
+            sequences(channel.history(HistoryQuery::SinceSequence(2))),
+            [3, 4]
+        );
+
+        let latest = channel.latest().unwrap().server_timestamp;
+        assert_eq!(
+            sequences(channel.history(HistoryQuery::SinceTimestamp(0))),
+            [2, 3, 4]
+        );
+        assert!(
+            channel
+                .history(HistoryQuery::SinceTimestamp(latest + 1))
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn retention() {
+        let mut channel = Channel::new(RetentionPolicy::Count(3));
+        for value in 1..=3 {
+            channel.push(supported_values::Kind::Int32(value), None);
+        }
+
+        channel.set_policy(RetentionPolicy::Latest);
+        assert_eq!(channel.values.items.len(), 1);
+        assert_eq!(channel.latest().unwrap().sequence, 3);
+
+        channel.set_policy(RetentionPolicy::WindowMs(60_000));
+        channel.push(supported_values::Kind::Int32(4), None);
+        assert_eq!(channel.values.items.len(), 2);
+
+        // values outside the window expire, but the latest one stays
+        let latest = channel.latest().unwrap().server_timestamp;
+        channel.expire(latest + 120_000_000);
+        assert_eq!(channel.values.items.len(), 1);
+        assert_eq!(channel.latest().unwrap().sequence, 4);
+    }
+
+    #[test]
+    fn restore() {
+        let mut channel = Channel::new(RetentionPolicy::Count(3));
+        let stored = |sequence| StoredValue {
+            kind: supported_values::Kind::Bool(true),
+            sequence,
+            server_timestamp: 1,
+            source_timestamp: None,
+        };
+        channel.restore(stored(4));
+        channel.restore(stored(2));
+        channel.restore(stored(5));
+        assert_eq!(channel.values.items.len(), 2);
+
+        let next = channel.push(supported_values::Kind::Bool(false), None);
+        assert_eq!(next.sequence, 6);
+
+        let stamped = next.to_stamped();
+        assert_eq!(
+            StoredValue::from_stamped(stamped).as_ref(),
+            channel.latest()
+        );
+    }
+
+    #[test]
+    fn atomic_operations() {
+        use supported_values::Kind;
+
+        assert_eq!(add(None, Kind::Int32(2)).unwrap(), Kind::Int32(2));
+        assert_eq!(
+            add(Some(&Kind::Double(1.5)), Kind::Double(0.25)).unwrap(),
+            Kind::Double(1.75)
+        );
+        assert!(add(Some(&Kind::Uint32(u32::MAX)), Kind::Uint32(1)).is_err());
+        assert!(add(Some(&Kind::Int32(1)), Kind::Int64(1)).is_err());
+        assert!(add(None, Kind::Bool(true)).is_err());
+
+        assert_eq!(toggle(None).unwrap(), Kind::Bool(true));
+        assert_eq!(toggle(Some(&Kind::Bool(true))).unwrap(), Kind::Bool(false));
+        assert!(toggle(Some(&Kind::Int32(0))).is_err());
+    }
+}
@@ -0,0 +1,84 @@
+use std::{fmt, io};
+
+#[derive(Debug)]
+pub enum XTablesError {
+    /// A socket could not be bound, usually because the port is taken.
+    Bind {
+        endpoint: String,
+        source: zmq::Error,
+    },
+    Zmq(zmq::Error),
+    Decode(prost::DecodeError),
+    /// A message decoded fine but is missing a required field.
+    Malformed(&'static str),
+    /// The configuration file or a retention rule is invalid.
+    Config(String),
+    /// Reading or writing the persistence files failed.
+    Io(io::Error),
+    /// An atomic operation does not apply to the value, e.g. adding to a string.
+    InvalidOperation(String),
+    /// A value does not have the type its channel is declared or locked to.
+    TypeMismatch {
+        channel: String,
+        expected: String,
+        found: String,
+    },
+}
+
+impl fmt::Display for XTablesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            XTablesError::Bind { endpoint, source } => {
+                write!(f, "failed to bind {}: {}", endpoint, source)
+            }
+            XTablesError::Zmq(err) => write!(f, "zmq error: {}", err),
+            XTablesError::Decode(err) => write!(f, "failed to decode message: {}", err),
+            XTablesError::Malformed(reason) => write!(f, "malformed message: {}", reason),
+            XTablesError::Config(reason) => write!(f, "invalid configuration: {}", reason),
+            XTablesError::Io(err) => write!(f, "i/o error: {}", err),
+            XTablesError::InvalidOperation(reason) => write!(f, "invalid operation: {}", reason),
+            XTablesError::TypeMismatch {
+                channel,
+                expected,
+                found,
+            } => write!(
+                f,
+                "type mismatch on channel {}: expected {}, found {}",
+                channel, expected, found
+            ),
+        }
+    }
+}
+
+impl std::error::Error for XTablesError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            XTablesError::Bind { source, .. } => Some(source),
+            XTablesError::Zmq(err) => Some(err),
+            XTablesError::Decode(err) => Some(err),
+            XTablesError::Io(err) => Some(err),
+            XTablesError::Malformed(_)
+            | XTablesError::Config(_)
+            | XTablesError::InvalidOperation(_)
+            | XTablesError::TypeMismatch { .. } => None,
+        }
+    }
+}
+
+impl From<zmq::Error> for XTablesError {
+    fn from(err: zmq::Error) -> Self {
+        XTablesError::Zmq(err)
+    }
+}
+
+impl From<io::Error> for XTablesError {
+    fn from(err: io::Error) -> Self {
+        XTablesError::Io(err)
+    }
+}
+
+impl From<prost::DecodeError> for XTablesError {
+    fn from(err: prost::DecodeError) -> Self {
+        XTablesError::Decode(err)
+    }
+}
@@ -1,8 +1,15 @@
 pub mod utils {
     pub mod args;
+    pub mod config;
     pub mod log;
     pub mod ports;
     pub mod ring_buffer;
+    pub mod transport;
 }
 
+pub mod channel;
+pub mod error;
+pub mod persistence;
+pub mod retention;
+pub mod schema;
 pub mod xtables_server;
@@ -3,9 +3,10 @@ use log::info;
 use xtables_server::{
     utils::{
         args::{CONFIG, XTablesArgs},
+        config::ServerConfig,
         log::init_logger,
     },
-    xtables_server::XTablesServer,
+    xtables_server::XTablesServerBuilder,
 };
 
 //simple usage of using xtables server and xtables client
@@ -17,15 +18,25 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     init_logger();
 
-    let xtables_server = XTablesServer::new();
-    xtables_server.start();
+    let args = CONFIG.get().expect("Configuration not set");
+    let mut builder = XTablesServerBuilder::from_args(args);
+    if let Some(path) = &args.config {
+        let config = ServerConfig::load(path)?;
+        builder = builder
+            .retention(config.retention_policies()?)
+            .schemas(config.schema_registry()?);
+        if let Some(persistence) = config.persistence {
+            builder = builder.persistence(persistence);
+        }
+    }
+    let xtables_server = builder.build()?;
+    xtables_server.start()?;
 
     info!("XTables server started successfully.");
 
-    // Prevent main from exiting
-    loop {
-        // Here you can add logic to interact with the server or handle other tasks
-        // For demonstration, we will just sleep for a while
-        tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
-    }
+    tokio::signal::ctrl_c().await?;
+    info!("Shutting down XTables server...");
+    // stop() joins the workers and writes the final snapshot
+    xtables_server.stop();
+    Ok(())
 }
@@ -0,0 +1,471 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use log::{info, warn};
+use prost::Message;
+use serde::Deserialize;
+use xtables_protobuf::protobuf::{ChannelSnapshot, Snapshot, WalRecord, wal_record};
+
+use crate::{
+    channel::{Channel, StoredValue},
+    error::XTablesError,
+    retention::{self, RetentionPolicies},
+};
+
+const DEFAULT_SNAPSHOT_INTERVAL_MS: u64 = 5_000;
+const DEFAULT_SYNC_INTERVAL_MS: u64 = 10;
+
+/// The `[persistence]` table of the config file.
+///
+/// ```toml
+/// [persistence]
+/// path = "xtables.db"
+/// channels = ["config/*"]
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PersistenceConfig {
+    /// Snapshot file. The write-ahead log lives next to it with a `.wal` extension.
+    pub path: PathBuf,
+    /// Channels to persist, a trailing `*` matches every channel with that prefix.
+    #[serde(default = "all_channels")]
+    pub channels: Vec<String>,
+    /// Persist every buffered value instead of only the latest one.
+    #[serde(default)]
+    pub history: bool,
+    #[serde(default = "default_snapshot_interval_ms")]
+    pub snapshot_interval_ms: u64,
+    /// How often the write-ahead log is flushed to disk, which bounds what a crash loses.
+    #[serde(default = "default_sync_interval_ms")]
+    pub sync_interval_ms: u64,
+}
+
+fn all_channels() -> Vec<String> {
+    vec![String::from("*")]
+}
+
+fn default_snapshot_interval_ms() -> u64 {
+    DEFAULT_SNAPSHOT_INTERVAL_MS
+}
+
+fn default_sync_interval_ms() -> u64 {
+    DEFAULT_SYNC_INTERVAL_MS
+}
+
+impl PersistenceConfig {
+    /// Persists the latest value of every channel to `path`.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        PersistenceConfig {
+            path: path.into(),
+            channels: all_channels(),
+            history: false,
+            snapshot_interval_ms: DEFAULT_SNAPSHOT_INTERVAL_MS,
+            sync_interval_ms: DEFAULT_SYNC_INTERVAL_MS,
+        }
+    }
+
+    pub fn channels(mut self, patterns: &[&str]) -> Self {
+        self.channels = patterns.iter().map(|pattern| pattern.to_string()).collect();
+        self
+    }
+
+    pub fn history(mut self, history: bool) -> Self {
+        self.history = history;
+        self
+    }
+
+    pub fn snapshot_interval(&self) -> Duration {
+        Duration::from_millis(self.snapshot_interval_ms)
+    }
+
+    pub fn sync_interval(&self) -> Duration {
+        Duration::from_millis(self.sync_interval_ms)
+    }
+
+    pub fn persists(&self, channel: &str) -> bool {
+        self.channels
+            .iter()
+            .any(|pattern| retention::pattern_matches(pattern, channel))
+    }
+
+    fn validate(&self) -> Result<(), XTablesError> {
+        for pattern in &self.channels {
+            retention::validate_pattern(pattern)?;
+        }
+        if self.snapshot_interval_ms == 0 || self.sync_interval_ms == 0 {
+            return Err(XTablesError::Config(String::from(
+                "persistence intervals must be at least 1 ms",
+            )));
+        }
+        Ok(())
+    }
+
+    fn wal_path(&self) -> PathBuf {
+        self.path.with_extension("wal")
+    }
+
+    /// The log being folded into a snapshot, only left behind if the server died meanwhile.
+    fn old_wal_path(&self) -> PathBuf {
+        self.path.with_extension("wal.old")
+    }
+}
+
+/// Keeps selected channels on disk as a periodic snapshot plus a write-ahead log of every
+/// change since that snapshot.
+///
+/// Snapshots are numbered, and every log starts with the number of the snapshot it follows.
+/// A log is only replayed on top of a snapshot it follows, so a crash in the middle of taking
+/// a snapshot never applies the same changes twice.
+pub struct Persistence {
+    config: PersistenceConfig,
+    wal: Mutex<WalFile>,
+    unsynced: AtomicBool,
+}
+
+struct WalFile {
+    file: File,
+    generation: u64,
+}
+
+impl Persistence {
+    /// Restores the channels stored at `config.path`, then compacts them into a fresh
+    /// snapshot so the log starts out empty.
+    pub fn open(
+        config: PersistenceConfig,
+        retention: &RetentionPolicies,
+    ) -> Result<(Self, HashMap<String, Channel>), XTablesError> {
+        config.validate()?;
+
+        let mut channels = HashMap::new();
+        let mut generation = 0;
+        match fs::read(&config.path) {
+            Ok(bytes) => {
+                let snapshot = Snapshot::decode(bytes.as_slice())?;
+                generation = snapshot.generation;
+                for snapshot in snapshot.channels {
+                    let channel = channels
+                        .entry(snapshot.channel.clone())
+                        .or_insert_with(|| Channel::new(retention.policy_for(&snapshot.channel)));
+                    for stamped in snapshot.values {
+                        if let Some(value) = StoredValue::from_stamped(stamped) {
+                            channel.restore(value);
+                        }
+                    }
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        for wal_path in [config.old_wal_path(), config.wal_path()] {
+            Self::replay(&wal_path, generation, &mut channels, retention)?;
+        }
+        channels.retain(|channel, _| config.persists(channel));
+
+        // once the new snapshot is written both logs are outdated, whether or not they are
+        // replaced before the next crash
+        let generation = generation + 1;
+        Self::write_snapshot(&config, &Self::collect(&config, &channels, generation))?;
+        let persistence = Persistence {
+            wal: Mutex::new(WalFile {
+                file: Self::create_wal(&config.wal_path(), generation)?,
+                generation,
+            }),
+            unsynced: AtomicBool::new(false),
+            config,
+        };
+        Self::remove(&persistence.config.old_wal_path())?;
+
+        info!(
+            "Restored {} channels from {}.",
+            channels.len(),
+            persistence.config.path.display()
+        );
+        Ok((persistence, channels))
+    }
+
+    pub fn config(&self) -> &PersistenceConfig {
+        &self.config
+    }
+
+    /// Creates an empty log at `path` that follows snapshot `generation`.
+    fn create_wal(path: &Path, generation: u64) -> Result<File, XTablesError> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+        let header = WalRecord {
+            channel: String::new(),
+            entry: Some(wal_record::Entry::Generation(generation)),
+        };
+        file.write_all(&header.encode_length_delimited_to_vec())?;
+        Ok(file)
+    }
+
+    fn remove(path: &Path) -> Result<(), XTablesError> {
+        match fs::remove_file(path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Applies the log at `path` if it follows snapshot `generation` or a later one.
+    fn replay(
+        path: &Path,
+        generation: u64,
+        channels: &mut HashMap<String, Channel>,
+        retention: &RetentionPolicies,
+    ) -> Result<(), XTablesError> {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut buffer = bytes.as_slice();
+        while !buffer.is_empty() {
+            // a crash in the middle of an append leaves a partial record at the end
+            let Ok(record) = WalRecord::decode_length_delimited(&mut buffer) else {
+                warn!(
+                    "Ignoring a truncated record at the end of {}.",
+                    path.display()
+                );
+                break;
+            };
+            match record.entry {
+                Some(wal_record::Entry::Generation(follows)) if follows < generation => {
+                    // the snapshot already contains this log
+                    return Ok(());
+                }
+                Some(wal_record::Entry::Generation(_)) | None => {}
+                Some(wal_record::Entry::Value(stamped)) => {
+                    if let Some(value) = StoredValue::from_stamped(stamped) {
+                        channels
+                            .entry(record.channel.clone())
+                            .or_insert_with(|| Channel::new(retention.policy_for(&record.channel)))
+                            .restore(value);
+                    }
+                }
+                Some(wal_record::Entry::Cleared(_)) => {
+                    if let Some(channel) = channels.get_mut(&record.channel) {
+                        channel.clear();
+                    }
+                }
+                Some(wal_record::Entry::Deleted(_)) => {
+                    channels.remove(&record.channel);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends a pushed value to the write-ahead log if its channel is persisted.
+    pub fn record(&self, channel: &str, value: &StoredValue) -> Result<(), XTablesError> {
+        self.append(channel, wal_record::Entry::Value(value.to_stamped()))
+    }
+
+    /// Appends the deletion of a channel, or only of its values when `deleted` is false.
+    pub fn record_removal(&self, channel: &str, deleted: bool) -> Result<(), XTablesError> {
+        let entry = if deleted {
+            wal_record::Entry::Deleted(true)
+        } else {
+            wal_record::Entry::Cleared(true)
+        };
+        self.append(channel, entry)
+    }
+
+    fn append(&self, channel: &str, entry: wal_record::Entry) -> Result<(), XTablesError> {
+        if !self.config.persists(channel) {
+            return Ok(());
+        }
+        let record = WalRecord {
+            channel: channel.to_string(),
+            entry: Some(entry),
+        }
+        .encode_length_delimited_to_vec();
+
+        self.wal.lock().unwrap().file.write_all(&record)?;
+        self.unsynced.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Flushes appended records to disk.
+    pub fn sync(&self) -> Result<(), XTablesError> {
+        if !self.unsynced.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+        // sync a duplicate handle so pushes can keep appending in the meantime
+        let wal = self.wal.lock().unwrap().file.try_clone()?;
+        wal.sync_data()?;
+        Ok(())
+    }
+
+    /// Writes the persisted channels to the snapshot file and starts an empty log.
+    pub fn snapshot(&self, channels: &Mutex<HashMap<String, Channel>>) -> Result<(), XTablesError> {
+        let snapshot = {
+            let channels = channels.lock().unwrap();
+            // changes are appended while holding the channel lock, so the new log only holds
+            // changes the snapshot misses
+            let mut wal = self.wal.lock().unwrap();
+            let generation = wal.generation + 1;
+            let snapshot = Self::collect(&self.config, &channels, generation);
+
+            fs::rename(self.config.wal_path(), self.config.old_wal_path())?;
+            *wal = WalFile {
+                file: Self::create_wal(&self.config.wal_path(), generation)?,
+                generation,
+            };
+            snapshot
+        };
+
+        Self::write_snapshot(&self.config, &snapshot)?;
+        Self::remove(&self.config.old_wal_path())
+    }
+
+    fn collect(
+        config: &PersistenceConfig,
+        channels: &HashMap<String, Channel>,
+        generation: u64,
+    ) -> Snapshot {
+        let channels = channels
+            .iter()
+            .filter(|(name, _)| config.persists(name))
+            .map(|(name, channel)| {
+                let values = if config.history {
+                    channel.values.items.iter().collect()
+                } else {
+                    channel.latest().into_iter().collect::<Vec<_>>()
+                };
+                ChannelSnapshot {
+                    channel: name.clone(),
+                    values: values.into_iter().map(StoredValue::to_stamped).collect(),
+                }
+            })
+            .collect();
+        Snapshot {
+            channels,
+            generation,
+        }
+    }
+
+    /// Replaces the snapshot file atomically, so a crash leaves either the old or the new one.
+    fn write_snapshot(config: &PersistenceConfig, snapshot: &Snapshot) -> Result<(), XTablesError> {
+        let temporary = config.path.with_extension("tmp");
+        let mut file = File::create(&temporary)?;
+        file.write_all(&snapshot.encode_to_vec())?;
+        file.sync_all()?;
+        fs::rename(&temporary, &config.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use xtables_protobuf::protobuf::supported_values;
+
+    use super::*;
+    use crate::retention::RetentionPolicy;
+
+    fn config(name: &str) -> PersistenceConfig {
+        let directory = env::temp_dir().join(format!("xtables-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        PersistenceConfig::new(directory.join("xtables.db"))
+    }
+
+    fn push(channels: &Mutex<HashMap<String, Channel>>, persistence: &Persistence, value: i32) {
+        let mut channels = channels.lock().unwrap();
+        let channel = channels
+            .entry(String::from("config/speed"))
+            .or_insert_with(|| Channel::new(RetentionPolicy::Count(10)));
+        let stored = channel.push(supported_values::Kind::Int32(value), None);
+        persistence.record("config/speed", stored).unwrap();
+    }
+
+    #[test]
+    fn restore() {
+        let config = config("restore").channels(&["config/*"]).history(true);
+        let retention = RetentionPolicies::default();
+
+        let (persistence, channels) = Persistence::open(config.clone(), &retention).unwrap();
+        let channels = Mutex::new(channels);
+        push(&channels, &persistence, 1);
+        persistence.snapshot(&channels).unwrap();
+        push(&channels, &persistence, 2);
+        push(&channels, &persistence, 3);
+        persistence.sync().unwrap();
+        drop(persistence);
+
+        // the second and third values only made it into the log
+        let (_, restored) = Persistence::open(config, &retention).unwrap();
+        let sequences: Vec<u64> = restored["config/speed"]
+            .values
+            .items
+            .iter()
+            .map(|value| value.sequence)
+            .collect();
+        assert_eq!(sequences, [1, 2, 3]);
+    }
+
+    #[test]
+    fn truncated_log() {
+        let config = config("truncated");
+        let retention = RetentionPolicies::default();
+
+        let (persistence, channels) = Persistence::open(config.clone(), &retention).unwrap();
+        let channels = Mutex::new(channels);
+        push(&channels, &persistence, 1);
+        push(&channels, &persistence, 2);
+        drop(persistence);
+
+        let wal = config.wal_path();
+        let length = fs::metadata(&wal).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&wal)
+            .unwrap()
+            .set_len(length - 1)
+            .unwrap();
+
+        let (_, restored) = Persistence::open(config, &retention).unwrap();
+        let latest = restored["config/speed"].latest().unwrap();
+        assert_eq!(latest.kind, supported_values::Kind::Int32(1));
+    }
+
+    #[test]
+    fn removal() {
+        let config = config("removal");
+        let retention = RetentionPolicies::default();
+
+        let (persistence, channels) = Persistence::open(config.clone(), &retention).unwrap();
+        let channels = Mutex::new(channels);
+        push(&channels, &persistence, 1);
+        push(&channels, &persistence, 2);
+        let stale_log = fs::read(config.wal_path()).unwrap();
+
+        channels.lock().unwrap().remove("config/speed");
+        persistence.record_removal("config/speed", true).unwrap();
+        push(&channels, &persistence, 3);
+        persistence.snapshot(&channels).unwrap();
+        drop(persistence);
+
+        // as if the server died before removing the log the snapshot replaced
+        fs::write(config.old_wal_path(), stale_log).unwrap();
+
+        let (_, restored) = Persistence::open(config, &retention).unwrap();
+        let latest = restored["config/speed"].latest().unwrap();
+        assert_eq!(latest.kind, supported_values::Kind::Int32(3));
+        assert_eq!(latest.sequence, 1);
+    }
+}
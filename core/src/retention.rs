@@ -0,0 +1,205 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::error::XTablesError;
+
+/// Upper bound on how many values a time window keeps, so a channel flooded with pushes
+/// cannot grow without limit.
+pub const MAX_WINDOW_VALUES: usize = 10_000;
+
+const DEFAULT_COUNT: usize = 100;
+
+/// How many values a channel keeps in its history.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetentionPolicy {
+    /// The most recent values, at most this many.
+    Count(usize),
+    /// Values received within this many milliseconds. The latest value is always kept.
+    WindowMs(u64),
+    /// Only the latest value.
+    Latest,
+}
+
+impl RetentionPolicy {
+    /// How many values the channel buffer has room for under this policy.
+    pub fn capacity(&self) -> usize {
+        match self {
+            RetentionPolicy::Count(count) => (*count).max(1),
+            RetentionPolicy::WindowMs(_) => MAX_WINDOW_VALUES,
+            RetentionPolicy::Latest => 1,
+        }
+    }
+
+    pub fn window(&self) -> Option<Duration> {
+        match self {
+            RetentionPolicy::WindowMs(window) => Some(Duration::from_millis(*window)),
+            _ => None,
+        }
+    }
+
+    fn validate(&self) -> Result<(), XTablesError> {
+        match self {
+            RetentionPolicy::Count(0) => Err(XTablesError::Config(String::from(
+                "retention count must be at least 1",
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy::Count(DEFAULT_COUNT)
+    }
+}
+
+/// Applies `policy` to the channel named `pattern`, or to every channel starting with the
+/// prefix when the pattern ends in `*`. The prefix is matched as a string, so `drive/*` also
+/// covers `drive/left/speed`, unlike the segment wildcards of client subscriptions.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct RetentionRule {
+    pub pattern: String,
+    pub policy: RetentionPolicy,
+}
+
+impl RetentionRule {
+    pub fn new(pattern: &str, policy: RetentionPolicy) -> Result<Self, XTablesError> {
+        let rule = RetentionRule {
+            pattern: pattern.to_string(),
+            policy,
+        };
+        rule.validate()?;
+        Ok(rule)
+    }
+
+    fn validate(&self) -> Result<(), XTablesError> {
+        validate_pattern(&self.pattern)?;
+        self.policy.validate()
+    }
+
+    pub fn matches(&self, channel: &str) -> bool {
+        specificity(&self.pattern, channel).is_some()
+    }
+}
+
+/// Checks that `pattern` is a channel name, or a prefix followed by a single trailing `*`.
+pub fn validate_pattern(pattern: &str) -> Result<(), XTablesError> {
+    let prefix = pattern.strip_suffix('*').unwrap_or(pattern);
+    if pattern.is_empty() || prefix.contains('*') {
+        return Err(XTablesError::Config(format!(
+            "invalid channel pattern '{}', only a trailing '*' is allowed",
+            pattern
+        )));
+    }
+    Ok(())
+}
+
+pub fn pattern_matches(pattern: &str, channel: &str) -> bool {
+    specificity(pattern, channel).is_some()
+}
+
+/// How closely `pattern` matches `channel`, exact names beat any prefix and longer prefixes
+/// beat shorter ones.
+fn specificity(pattern: &str, channel: &str) -> Option<usize> {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => channel.starts_with(prefix).then_some(prefix.len()),
+        None => (pattern == channel).then_some(usize::MAX),
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RetentionPolicies {
+    /// Policy of channels no rule matches.
+    default: RetentionPolicy,
+    rules: Vec<RetentionRule>,
+}
+
+impl RetentionPolicies {
+    pub fn new(default: RetentionPolicy) -> Result<Self, XTablesError> {
+        default.validate()?;
+        Ok(RetentionPolicies {
+            default,
+            rules: Vec::new(),
+        })
+    }
+
+    /// Adds `rule`, replacing an earlier rule with the same pattern.
+    pub fn set(&mut self, rule: RetentionRule) -> Result<(), XTablesError> {
+        rule.validate()?;
+        match self
+            .rules
+            .iter_mut()
+            .find(|existing| existing.pattern == rule.pattern)
+        {
+            Some(existing) => existing.policy = rule.policy,
+            None => self.rules.push(rule),
+        }
+        Ok(())
+    }
+
+    pub fn rules(&self) -> &[RetentionRule] {
+        &self.rules
+    }
+
+    pub fn policy_for(&self, channel: &str) -> RetentionPolicy {
+        self.rules
+            .iter()
+            .filter_map(|rule| Some((specificity(&rule.pattern, channel)?, rule.policy)))
+            .max_by_key(|(specificity, _)| *specificity)
+            .map(|(_, policy)| policy)
+            .unwrap_or(self.default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns() {
+        assert!(RetentionRule::new("drive/*", RetentionPolicy::Latest).is_ok());
+        assert!(RetentionRule::new("*", RetentionPolicy::Latest).is_ok());
+        assert!(RetentionRule::new("", RetentionPolicy::Latest).is_err());
+        assert!(RetentionRule::new("drive/*/speed", RetentionPolicy::Latest).is_err());
+        assert!(RetentionRule::new("drive", RetentionPolicy::Count(0)).is_err());
+    }
+
+    #[test]
+    fn policy_for() {
+        let mut policies = RetentionPolicies::default();
+        policies
+            .set(RetentionRule::new("sensors/*", RetentionPolicy::Count(10)).unwrap())
+            .unwrap();
+        policies
+            .set(RetentionRule::new("sensors/gyro*", RetentionPolicy::WindowMs(500)).unwrap())
+            .unwrap();
+        policies
+            .set(RetentionRule::new("sensors/gyro/yaw", RetentionPolicy::Latest).unwrap())
+            .unwrap();
+
+        assert_eq!(policies.policy_for("other"), RetentionPolicy::Count(100));
+        assert_eq!(
+            policies.policy_for("sensors/range"),
+            RetentionPolicy::Count(10)
+        );
+        assert_eq!(
+            policies.policy_for("sensors/gyro/pitch"),
+            RetentionPolicy::WindowMs(500)
+        );
+        assert_eq!(
+            policies.policy_for("sensors/gyro/yaw"),
+            RetentionPolicy::Latest
+        );
+
+        policies
+            .set(RetentionRule::new("sensors/*", RetentionPolicy::Count(20)).unwrap())
+            .unwrap();
+        assert_eq!(policies.rules().len(), 3);
+        assert_eq!(
+            policies.policy_for("sensors/range"),
+            RetentionPolicy::Count(20)
+        );
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+
+use xtables_protobuf::protobuf::supported_values;
+
+use crate::error::XTablesError;
+
+/// The value type a channel accepts.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChannelSchema {
+    /// One of [`supported_values::Kind::TYPE_NAMES`], e.g. `"double"`.
+    pub value_type: String,
+    /// Set by a declaration rather than taken from the first value written, declared types
+    /// outlive the channel being deleted.
+    pub declared: bool,
+}
+
+/// Types of the channels. A channel takes the type of the first value written to it unless
+/// one was declared beforehand, and rejects values of any other type from then on.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SchemaRegistry {
+    schemas: HashMap<String, ChannelSchema>,
+}
+
+impl SchemaRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Declares `channel` to hold `value_type`. Fails if the type is unknown or the channel
+    /// already has a different type.
+    pub fn declare(&mut self, channel: &str, value_type: &str) -> Result<(), XTablesError> {
+        if !supported_values::Kind::TYPE_NAMES.contains(&value_type) {
+            return Err(XTablesError::Config(format!(
+                "unknown value type '{}' for channel '{}'",
+                value_type, channel
+            )));
+        }
+        match self.schemas.get_mut(channel) {
+            Some(schema) if schema.value_type != value_type => Err(XTablesError::TypeMismatch {
+                channel: channel.to_string(),
+                expected: schema.value_type.clone(),
+                found: value_type.to_string(),
+            }),
+            Some(schema) => {
+                schema.declared = true;
+                Ok(())
+            }
+            None => {
+                self.schemas.insert(
+                    channel.to_string(),
+                    ChannelSchema {
+                        value_type: value_type.to_string(),
+                        declared: true,
+                    },
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// Checks that `kind` fits `channel`, locking the channel to its type if it has none yet.
+    pub fn check(
+        &mut self,
+        channel: &str,
+        kind: &supported_values::Kind,
+    ) -> Result<(), XTablesError> {
+        self.verify(channel, kind)?;
+        if !self.schemas.contains_key(channel) {
+            self.schemas.insert(
+                channel.to_string(),
+                ChannelSchema {
+                    value_type: kind.type_name().to_string(),
+                    declared: false,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// Like `check`, without locking the type of an untyped channel.
+    pub fn verify(&self, channel: &str, kind: &supported_values::Kind) -> Result<(), XTablesError> {
+        match self.schemas.get(channel) {
+            Some(schema) if schema.value_type != kind.type_name() => {
+                Err(XTablesError::TypeMismatch {
+                    channel: channel.to_string(),
+                    expected: schema.value_type.clone(),
+                    found: kind.type_name().to_string(),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Forgets the type of a deleted channel, unless it was declared.
+    pub fn forget(&mut self, channel: &str) {
+        if self
+            .schemas
+            .get(channel)
+            .is_some_and(|schema| !schema.declared)
+        {
+            self.schemas.remove(channel);
+        }
+    }
+
+    pub fn get(&self, channel: &str) -> Option<&ChannelSchema> {
+        self.schemas.get(channel)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &ChannelSchema)> {
+        self.schemas
+            .iter()
+            .map(|(channel, schema)| (channel.as_str(), schema))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock_on_first_write() {
+        let mut schemas = SchemaRegistry::new();
+
+        assert!(
+            schemas
+                .check("arm/angle", &supported_values::Kind::Double(1.0))
+                .is_ok()
+        );
+        assert!(
+            schemas
+                .check("arm/angle", &supported_values::Kind::Double(2.0))
+                .is_ok()
+        );
+        assert!(matches!(
+            schemas.check(
+                "arm/angle",
+                &supported_values::Kind::String(String::from("up"))
+            ),
+            Err(XTablesError::TypeMismatch { .. })
+        ));
+
+        schemas.forget("arm/angle");
+        assert!(schemas.get("arm/angle").is_none());
+        assert!(
+            schemas
+                .verify("arm/angle", &supported_values::Kind::Int32(1))
+                .is_ok()
+        );
+        assert!(schemas.get("arm/angle").is_none());
+        assert!(
+            schemas
+                .check("arm/angle", &supported_values::Kind::Bool(true))
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn declare() {
+        let mut schemas = SchemaRegistry::new();
+
+        schemas.declare("mode", "string").unwrap();
+        assert!(matches!(
+            schemas.check("mode", &supported_values::Kind::Int32(1)),
+            Err(XTablesError::TypeMismatch { .. })
+        ));
+        schemas.forget("mode");
+        assert!(schemas.get("mode").unwrap().declared);
+
+        assert!(schemas.declare("mode", "string").is_ok());
+        assert!(schemas.declare("mode", "double").is_err());
+        assert!(schemas.declare("speed", "quaternion").is_err());
+
+        schemas
+            .check("speed", &supported_values::Kind::Float(1.0))
+            .unwrap();
+        schemas.declare("speed", "float").unwrap();
+        assert!(schemas.get("speed").unwrap().declared);
+    }
+}
@@ -1,6 +1,8 @@
-use std::sync::OnceLock;
+use std::{path::PathBuf, sync::OnceLock};
 
-use clap::{Parser, command};
+use clap::Parser;
+
+use crate::utils::{ports, transport::Transport};
 
 // XTables server configuration
 #[derive(Parser, Debug)]
@@ -9,6 +11,42 @@ pub struct XTablesArgs {
     /// Enable logging for the XTables server
     #[arg(short, long, default_value_t = false)]
     pub log: bool,
+
+    /// Interface the tcp sockets bind to
+    #[arg(long, env = "XTABLES_INTERFACE", default_value = "*")]
+    pub interface: String,
+
+    /// Port of the PUB socket that broadcasts updates
+    #[arg(long, env = "XTABLES_PUB_PORT", default_value_t = ports::DEFAULT_PUB_SUB_PORT)]
+    pub pub_port: u16,
+
+    /// Port of the PULL socket that receives pushed values
+    #[arg(long, env = "XTABLES_PULL_PORT", default_value_t = ports::DEFAULT_PUSH_PULL_PORT)]
+    pub pull_port: u16,
+
+    /// Port of the ROUTER socket that answers requests
+    #[arg(long, env = "XTABLES_REP_PORT", default_value_t = ports::DEFAULT_REQ_REP_PORT)]
+    pub rep_port: u16,
+
+    /// Transport of the PUB socket
+    #[arg(long, env = "XTABLES_PUB_TRANSPORT", value_enum, default_value_t = Transport::Tcp)]
+    pub pub_transport: Transport,
+
+    /// Transport of the PULL socket
+    #[arg(long, env = "XTABLES_PULL_TRANSPORT", value_enum, default_value_t = Transport::Tcp)]
+    pub pull_transport: Transport,
+
+    /// Transport of the ROUTER socket
+    #[arg(long, env = "XTABLES_REP_TRANSPORT", value_enum, default_value_t = Transport::Tcp)]
+    pub rep_transport: Transport,
+
+    /// TOML file with retention rules, persistence settings and channel schemas
+    #[arg(long, env = "XTABLES_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Token clients must send to change retention or declare channel types at runtime
+    #[arg(long, env = "XTABLES_ADMIN_TOKEN")]
+    pub admin_token: Option<String>,
 }
 
 pub static CONFIG: OnceLock<XTablesArgs> = OnceLock::new();
@@ -0,0 +1,143 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use serde::Deserialize;
+
+use crate::{
+    error::XTablesError,
+    persistence::PersistenceConfig,
+    retention::{RetentionPolicies, RetentionPolicy, RetentionRule},
+    schema::SchemaRegistry,
+};
+
+/// Contents of the file passed with `--config`.
+///
+/// Retention and persistence patterns are a channel name or a prefix ending in `*`, which
+/// matches across segments: `config/*` covers `config/arm/angle`. Client subscriptions use
+/// segment wildcards instead, where `*` stands for exactly one segment.
+///
+/// ```toml
+/// default_retention = { count = 100 }
+///
+/// [[retention]]
+/// pattern = "sensors/gyro/*"
+/// policy = { window_ms = 500 }
+///
+/// [[retention]]
+/// pattern = "config/*"
+/// policy = "latest"
+///
+/// [persistence]
+/// path = "xtables.db"
+/// channels = ["config/*"]
+///
+/// [schemas]
+/// "arm/angle" = "double"
+/// "auto/mode" = "string"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    #[serde(default)]
+    pub default_retention: RetentionPolicy,
+    #[serde(default)]
+    pub retention: Vec<RetentionRule>,
+    pub persistence: Option<PersistenceConfig>,
+    /// Value types of channels, keyed by channel name.
+    #[serde(default)]
+    pub schemas: BTreeMap<String, String>,
+}
+
+impl ServerConfig {
+    pub fn load(path: &Path) -> Result<Self, XTablesError> {
+        let contents = fs::read_to_string(path).map_err(|err| {
+            XTablesError::Config(format!("failed to read {}: {}", path.display(), err))
+        })?;
+        Self::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, XTablesError> {
+        toml::from_str(contents).map_err(|err| XTablesError::Config(err.to_string()))
+    }
+
+    pub fn retention_policies(&self) -> Result<RetentionPolicies, XTablesError> {
+        let mut policies = RetentionPolicies::new(self.default_retention)?;
+        for rule in &self.retention {
+            policies.set(rule.clone())?;
+        }
+        Ok(policies)
+    }
+
+    pub fn schema_registry(&self) -> Result<SchemaRegistry, XTablesError> {
+        let mut schemas = SchemaRegistry::new();
+        for (channel, value_type) in &self.schemas {
+            schemas.declare(channel, value_type)?;
+        }
+        Ok(schemas)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let config = ServerConfig::parse(
+            r#"
+            default_retention = { count = 20 }
+
+            [[retention]]
+            pattern = "sensors/gyro/*"
+            policy = { window_ms = 500 }
+
+            [[retention]]
+            pattern = "config/*"
+            policy = "latest"
+
+            [persistence]
+            path = "xtables.db"
+            channels = ["config/*"]
+
+            [schemas]
+            "arm/angle" = "double"
+            "#,
+        )
+        .unwrap();
+        let policies = config.retention_policies().unwrap();
+
+        assert_eq!(policies.policy_for("drive"), RetentionPolicy::Count(20));
+        assert_eq!(
+            policies.policy_for("sensors/gyro/yaw"),
+            RetentionPolicy::WindowMs(500)
+        );
+        assert_eq!(policies.policy_for("config/name"), RetentionPolicy::Latest);
+
+        let persistence = config.persistence.as_ref().unwrap();
+        assert!(persistence.persists("config/name"));
+        assert!(!persistence.persists("drive"));
+        assert!(!persistence.history);
+
+        let schemas = config.schema_registry().unwrap();
+        assert_eq!(schemas.get("arm/angle").unwrap().value_type, "double");
+        assert!(
+            ServerConfig::parse("[schemas]\n\"arm/angle\" = \"angle\"")
+                .unwrap()
+                .schema_registry()
+                .is_err()
+        );
+
+        assert!(
+            ServerConfig::parse("")
+                .unwrap()
+                .retention_policies()
+                .is_ok()
+        );
+        assert!(ServerConfig::parse("unknown = 1").is_err());
+        assert!(
+            ServerConfig::parse("[[retention]]\npattern = \"a*b\"\npolicy = \"latest\"")
+                .unwrap()
+                .retention_policies()
+                .is_err()
+        );
+    }
+}
@@ -1,6 +1,9 @@
 use log::{LevelFilter, Log, Metadata, Record};
 use once_cell::sync::Lazy;
-use std::sync::{Mutex, Once};
+use std::{
+    sync::{Condvar, Mutex, Once},
+    time::Duration,
+};
 
 use crate::utils::{args::CONFIG, ring_buffer::RingBuffer};
 
@@ -8,6 +11,7 @@ use crate::utils::{args::CONFIG, ring_buffer::RingBuffer};
 pub struct XTablesLogger {
     logs: Mutex<RingBuffer<String>>,
     unread_logs: Mutex<Vec<String>>,
+    unread_available: Condvar,
 }
 
 impl Log for XTablesLogger {
@@ -42,6 +46,7 @@ impl Log for XTablesLogger {
                     record.target(),
                     record.args()
                 ));
+                self.unread_available.notify_all();
             }
         }
     }
@@ -66,11 +71,23 @@ impl XTablesLogger {
             None
         }
     }
+
+    /// Like `read_unread_logs`, but blocks for up to `timeout` until a log arrives.
+    pub fn wait_unread_logs(&self, timeout: Duration) -> Option<Vec<String>> {
+        let unread = self.unread_logs.lock().ok()?;
+        let (mut unread, _) = self
+            .unread_available
+            .wait_timeout_while(unread, timeout, |unread| unread.is_empty())
+            .ok()?;
+        let logs: Vec<String> = unread.drain(..).collect();
+        if logs.is_empty() { None } else { Some(logs) }
+    }
 }
 
 pub static LOGGER: Lazy<XTablesLogger> = Lazy::new(|| XTablesLogger {
     logs: Mutex::new(RingBuffer::new(500)),
     unread_logs: Mutex::new(Vec::new()),
+    unread_available: Condvar::new(),
 });
 
 static INIT: Once = Once::new();
@@ -33,6 +33,14 @@ impl<T> RingBuffer<T> {
     pub fn peek(&self) -> Option<&T> {
         self.items.back()
     }
+
+    /// Changes how many items the buffer holds, dropping the oldest ones that no longer fit.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        while self.items.len() > capacity {
+            self.items.pop_front();
+        }
+        self.capacity = capacity;
+    }
 }
 
 #[cfg(test)]
@@ -109,4 +117,23 @@ mod tests {
 
         assert_eq!(buffer.pop(), None);
     }
+
+    #[test]
+    fn set_capacity() {
+        let mut buffer: RingBuffer<i32> = RingBuffer::new(3);
+
+        buffer.push(1);
+        buffer.push(2);
+        buffer.push(3);
+
+        buffer.set_capacity(2);
+
+        assert_eq!(buffer.items, [2, 3]);
+
+        buffer.set_capacity(4);
+        buffer.push(4);
+        buffer.push(5);
+
+        assert_eq!(buffer.items, [2, 3, 4, 5]);
+    }
 }
@@ -0,0 +1,39 @@
+use clap::ValueEnum;
+
+/// ZeroMQ transport used by one of the server sockets.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Transport {
+    /// `tcp://<interface>:<port>`
+    #[default]
+    Tcp,
+    /// `ipc://xtables-<port>.ipc`, relative to the working directory
+    Ipc,
+}
+
+impl Transport {
+    pub fn endpoint(&self, interface: &str, port: u16) -> String {
+        match self {
+            Transport::Tcp => format!("tcp://{}:{}", interface, port),
+            Transport::Ipc => format!("ipc://xtables-{}.ipc", port),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tcp_endpoint() {
+        assert_eq!(Transport::Tcp.endpoint("*", 5555), "tcp://*:5555");
+        assert_eq!(
+            Transport::Tcp.endpoint("127.0.0.1", 6000),
+            "tcp://127.0.0.1:6000"
+        );
+    }
+
+    #[test]
+    fn ipc_endpoint() {
+        assert_eq!(Transport::Ipc.endpoint("*", 5555), "ipc://xtables-5555.ipc");
+    }
+}
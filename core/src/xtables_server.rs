@@ -1,340 +1,1827 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     io::Cursor,
     sync::{
         Arc, Mutex,
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, RecvTimeoutError},
     },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
-use crate::utils::{log::LOGGER, ports, ring_buffer::RingBuffer};
-use log::info;
+use crate::{
+    channel::{self, Channel, HistoryQuery, StoredValue},
+    error::XTablesError,
+    persistence::{Persistence, PersistenceConfig},
+    retention::{RetentionPolicies, RetentionPolicy, RetentionRule},
+    schema::SchemaRegistry,
+    utils::{args::XTablesArgs, log::LOGGER, ports, transport::Transport},
+};
+use log::{error, info, warn};
 use prost::Message;
-use tokio::task;
-use xtables_protobuf::protobuf::{
-    Publish, Push, Reply, ReplyDataCommand, ReplyLogsCommand, Request, SendDataCommand,
-    SendLogsCommand, SupportedValues, publish, push, reply, request, supported_values,
+use xtables_protobuf::{
+    LEGACY_NO_DATA, PROTOCOL_VERSION,
+    protobuf::{
+        ChannelInfo, ChannelSchema, HeartbeatCommand, NotFound, Publish, PublishBatchCommand,
+        PublishDataCommand, PublishRemovalCommand, Push, Reply, ReplyAckCommand,
+        ReplyChannelsCommand, ReplyDataCommand, ReplyErrorCommand, ReplyHistoryCommand,
+        ReplyLogsCommand, ReplySchemasCommand, ReplyWriteCommand, Request, SendBatchCommand,
+        SendLogsCommand, SetRetentionCommand, SupportedValues, compare_and_set_command,
+        get_history_command, publish, push, reply, reply_data_command, request, retention_policy,
+        supported_values,
+    },
+    topic,
 };
 
 use zmq::{
     Context, SNDMORE,
-    SocketType::{PUB, PULL, REP},
+    SocketType::{PUB, PULL, ROUTER},
 };
 
 const DEFAULT_REP_PORT: u16 = ports::DEFAULT_REQ_REP_PORT;
 const DEFAULT_PUB_PORT: u16 = ports::DEFAULT_PUB_SUB_PORT;
 const DEFAULT_PULL_PORT: u16 = ports::DEFAULT_PUSH_PULL_PORT;
 
+type ChannelMap = Mutex<HashMap<String, Channel>>;
+
+/// State the worker threads share with the server handle.
+///
+/// Whenever several locks are needed they are taken in the order `retention`, `schemas`,
+/// `cached_messages`.
+struct ServerState {
+    cached_messages: ChannelMap,
+    retention: Mutex<RetentionPolicies>,
+    schemas: Mutex<SchemaRegistry>,
+    admin_token: Option<String>,
+    persistence: Option<Persistence>,
+    rejected_messages: AtomicU64,
+    type_mismatches: AtomicU64,
+    /// Batches published so far, numbering the next one.
+    batches: AtomicU64,
+}
+
+/// How long the worker loops wait for a message before checking whether they should stop.
+const POLL_TIMEOUT_MS: i64 = 100;
+
+/// How often the server publishes a heartbeat. Clients consider it gone after missing a few.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often the server logs how many messages it rejected, if that number went up.
+const REJECTION_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+const REBIND_ATTEMPTS: u32 = 20;
+const REBIND_INTERVAL: Duration = Duration::from_millis(25);
+
+struct ServerSockets {
+    pub_socket: zmq::Socket,
+    pull_socket: zmq::Socket,
+    router_socket: zmq::Socket,
+}
+
+struct ServerEndpoints {
+    pub_endpoint: String,
+    pull_endpoint: String,
+    router_endpoint: String,
+}
+
 pub struct XTablesServer {
-    pub_socket: Arc<Mutex<zmq::Socket>>,
-    pull_socket: Arc<Mutex<zmq::Socket>>,
-    rep_socket: Arc<Mutex<zmq::Socket>>,
-    cached_messages: Arc<Mutex<HashMap<String, RingBuffer<supported_values::Kind>>>>,
+    context: Context,
+    endpoints: ServerEndpoints,
+    /// Sockets bound ahead of `start`, handed over to the worker threads when they spawn.
+    sockets: Mutex<Option<ServerSockets>>,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+    state: Arc<ServerState>,
     stop: Arc<AtomicBool>,
-    initialized: Arc<AtomicBool>,
+    /// Dropped by `stop` to wake the persist worker, which otherwise sleeps until its next
+    /// sync.
+    persist_wakeup: Mutex<Option<mpsc::Sender<()>>>,
 }
 
-impl XTablesServer {
+pub struct XTablesServerBuilder {
+    interface: String,
+    pub_port: u16,
+    pull_port: u16,
+    rep_port: u16,
+    pub_transport: Transport,
+    pull_transport: Transport,
+    rep_transport: Transport,
+    retention: RetentionPolicies,
+    schemas: SchemaRegistry,
+    admin_token: Option<String>,
+    persistence: Option<PersistenceConfig>,
+}
+
+impl XTablesServerBuilder {
     pub fn new() -> Self {
+        XTablesServerBuilder {
+            interface: String::from("*"),
+            pub_port: DEFAULT_PUB_PORT,
+            pull_port: DEFAULT_PULL_PORT,
+            rep_port: DEFAULT_REP_PORT,
+            pub_transport: Transport::Tcp,
+            pull_transport: Transport::Tcp,
+            rep_transport: Transport::Tcp,
+            retention: RetentionPolicies::default(),
+            schemas: SchemaRegistry::new(),
+            admin_token: None,
+            persistence: None,
+        }
+    }
+
+    pub fn from_args(args: &XTablesArgs) -> Self {
+        XTablesServerBuilder {
+            interface: args.interface.clone(),
+            pub_port: args.pub_port,
+            pull_port: args.pull_port,
+            rep_port: args.rep_port,
+            pub_transport: args.pub_transport,
+            pull_transport: args.pull_transport,
+            rep_transport: args.rep_transport,
+            retention: RetentionPolicies::default(),
+            schemas: SchemaRegistry::new(),
+            admin_token: args.admin_token.clone(),
+            persistence: None,
+        }
+    }
+
+    /// Interface the tcp sockets bind to, `*` binds all of them.
+    pub fn interface(mut self, interface: &str) -> Self {
+        self.interface = interface.to_string();
+        self
+    }
+
+    pub fn pub_port(mut self, port: u16) -> Self {
+        self.pub_port = port;
+        self
+    }
+
+    pub fn pull_port(mut self, port: u16) -> Self {
+        self.pull_port = port;
+        self
+    }
+
+    pub fn rep_port(mut self, port: u16) -> Self {
+        self.rep_port = port;
+        self
+    }
+
+    pub fn pub_transport(mut self, transport: Transport) -> Self {
+        self.pub_transport = transport;
+        self
+    }
+
+    pub fn pull_transport(mut self, transport: Transport) -> Self {
+        self.pull_transport = transport;
+        self
+    }
+
+    pub fn rep_transport(mut self, transport: Transport) -> Self {
+        self.rep_transport = transport;
+        self
+    }
+
+    /// How many values each channel keeps, see [`crate::utils::config::ServerConfig`] to
+    /// load them from a file.
+    pub fn retention(mut self, retention: RetentionPolicies) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Declared value types of channels. Channels without one take the type of the first
+    /// value written to them.
+    pub fn schemas(mut self, schemas: SchemaRegistry) -> Self {
+        self.schemas = schemas;
+        self
+    }
+
+    /// Token clients must present to change retention or declare channel types at runtime.
+    /// Without one, runtime changes are refused.
+    pub fn admin_token(mut self, token: &str) -> Self {
+        self.admin_token = Some(token.to_string());
+        self
+    }
+
+    /// Keeps channels on disk and restores them when the server is built.
+    pub fn persistence(mut self, config: PersistenceConfig) -> Self {
+        self.persistence = Some(config);
+        self
+    }
+
+    pub fn build(self) -> Result<XTablesServer, XTablesError> {
         let context = Context::new();
 
-        let cached_messages = Arc::new(Mutex::new(HashMap::new()));
+        let endpoints = ServerEndpoints {
+            pub_endpoint: self.pub_transport.endpoint(&self.interface, self.pub_port),
+            pull_endpoint: self
+                .pull_transport
+                .endpoint(&self.interface, self.pull_port),
+            router_endpoint: self.rep_transport.endpoint(&self.interface, self.rep_port),
+        };
+        let sockets = XTablesServer::bind_sockets(&context, &endpoints)?;
 
-        let stop = Arc::new(AtomicBool::new(false));
-        let initialized = Arc::new(AtomicBool::new(false));
+        let (persistence, channels) = match self.persistence {
+            Some(config) => {
+                let (persistence, channels) = Persistence::open(config, &self.retention)?;
+                (Some(persistence), channels)
+            }
+            None => (None, HashMap::new()),
+        };
 
-        let pub_socket = Arc::new(Mutex::new(context.socket(PUB).unwrap()));
-        let pull_socket = Arc::new(Mutex::new(context.socket(PULL).unwrap()));
-        let rep_socket = Arc::new(Mutex::new(context.socket(REP).unwrap()));
+        // restored channels keep the type they had before the restart
+        let mut schemas = self.schemas;
+        for (name, channel) in &channels {
+            if let Some(latest) = channel.latest()
+                && let Err(err) = schemas.check(name, &latest.kind)
+            {
+                warn!("Restored channel does not match its schema: {}", err);
+            }
+        }
 
-        pub_socket
-            .lock()
-            .unwrap()
-            .bind(&format!("tcp://*:{}", DEFAULT_PUB_PORT))
-            .unwrap();
-        pull_socket
-            .lock()
-            .unwrap()
-            .bind(&format!("tcp://*:{}", DEFAULT_PULL_PORT))
-            .unwrap();
-        rep_socket
-            .lock()
-            .unwrap()
-            .bind(&format!("tcp://*:{}", DEFAULT_REP_PORT))
-            .unwrap();
+        Ok(XTablesServer {
+            context,
+            endpoints,
+            sockets: Mutex::new(Some(sockets)),
+            workers: Mutex::new(Vec::new()),
+            state: Arc::new(ServerState {
+                cached_messages: Mutex::new(channels),
+                retention: Mutex::new(self.retention),
+                schemas: Mutex::new(schemas),
+                admin_token: self.admin_token,
+                persistence,
+                rejected_messages: AtomicU64::new(0),
+                type_mismatches: AtomicU64::new(0),
+                batches: AtomicU64::new(0),
+            }),
+            stop: Arc::new(AtomicBool::new(false)),
+            persist_wakeup: Mutex::new(None),
+        })
+    }
+}
+
+impl Default for XTablesServerBuilder {
+    fn default() -> Self {
+        XTablesServerBuilder::new()
+    }
+}
 
-        XTablesServer {
+impl XTablesServer {
+    /// Binds the default ports on every interface, panicking if any of them is taken.
+    /// Use [`XTablesServer::builder`] to pick the addresses and handle bind errors.
+    pub fn new() -> Self {
+        XTablesServer::builder()
+            .build()
+            .expect("Failed to bind XTables server sockets")
+    }
+
+    pub fn builder() -> XTablesServerBuilder {
+        XTablesServerBuilder::new()
+    }
+
+    fn bind(socket: &zmq::Socket, endpoint: &str) -> Result<(), XTablesError> {
+        socket.bind(endpoint).map_err(|source| XTablesError::Bind {
+            endpoint: endpoint.to_string(),
+            source,
+        })
+    }
+
+    fn bind_sockets(
+        context: &Context,
+        endpoints: &ServerEndpoints,
+    ) -> Result<ServerSockets, XTablesError> {
+        let pub_socket = context.socket(PUB)?;
+        let pull_socket = context.socket(PULL)?;
+        let router_socket = context.socket(ROUTER)?;
+
+        // drop queued messages on close so stop() never waits on absent peers
+        for socket in [&pub_socket, &pull_socket, &router_socket] {
+            socket.set_linger(0)?;
+        }
+
+        Self::bind(&pub_socket, &endpoints.pub_endpoint)?;
+        Self::bind(&pull_socket, &endpoints.pull_endpoint)?;
+        Self::bind(&router_socket, &endpoints.router_endpoint)?;
+
+        Ok(ServerSockets {
             pub_socket,
             pull_socket,
-            rep_socket,
-            cached_messages,
-            stop,
-            initialized,
+            router_socket,
+        })
+    }
+
+    /// zmq closes sockets in the background, so right after `stop` the old listeners can
+    /// still hold their ports for a moment.
+    fn rebind_sockets(&self) -> Result<ServerSockets, XTablesError> {
+        let mut attempts = 1;
+        loop {
+            match Self::bind_sockets(&self.context, &self.endpoints) {
+                Err(XTablesError::Bind {
+                    source: zmq::Error::EADDRINUSE,
+                    ..
+                }) if attempts < REBIND_ATTEMPTS => {
+                    attempts += 1;
+                    thread::sleep(REBIND_INTERVAL);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn spawn_worker<F>(name: &str, worker: F) -> JoinHandle<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        thread::Builder::new()
+            .name(name.to_string())
+            .spawn(worker)
+            .expect("Failed to spawn XTables worker thread")
+    }
+
+    /// Waits up to `POLL_TIMEOUT_MS` for `socket` to become readable.
+    fn wait_readable(socket: &zmq::Socket) -> bool {
+        match socket.poll(zmq::POLLIN, POLL_TIMEOUT_MS) {
+            Ok(ready) => ready > 0,
+            Err(err) => {
+                error!("Failed to poll socket: {}", err);
+                false
+            }
         }
     }
 
-    fn publish_data(channel: &str, data: supported_values::Kind) -> Vec<u8> {
+    fn data_command(channel: &str, data: &StoredValue) -> PublishDataCommand {
+        PublishDataCommand {
+            channel: channel.to_string(),
+            value: Some(SupportedValues {
+                kind: Some(data.kind.clone()),
+            }),
+            source_timestamp: data.source_timestamp,
+            server_timestamp: data.server_timestamp,
+            sequence: data.sequence,
+        }
+    }
+
+    fn publish_data(channel: &str, data: &StoredValue) -> Vec<u8> {
         Publish {
-            payload: Some(publish::Payload::Data(SendDataCommand {
-                channel: channel.to_string(),
-                value: Some(SupportedValues { kind: Some(data) }),
-            })),
+            payload: Some(publish::Payload::Data(Self::data_command(channel, data))),
         }
         .encode_to_vec()
     }
 
-    pub fn start(&self) {
-        if !self.initialized.load(Ordering::SeqCst) {
-            info!("Initializing XTables server...");
-            self.initialized.store(true, Ordering::SeqCst);
-        } else if self.stop.load(Ordering::SeqCst) {
-            info!("Starting XTables server...");
-            self.stop.store(false, Ordering::SeqCst);
+    /// Deletes `channel`, or only clears its values, and tells its subscribers. Channels that
+    /// do not exist are left alone.
+    fn handle_removal(
+        state: &ServerState,
+        pub_socket: &Mutex<zmq::Socket>,
+        channel: &str,
+        deleted: bool,
+    ) -> reply::Payload {
+        let mut schemas = state.schemas.lock().unwrap();
+        let mut channels = state.cached_messages.lock().unwrap();
+        let removed = if deleted {
+            schemas.forget(channel);
+            channels.remove(channel).is_some()
         } else {
+            channels.get_mut(channel).map(Channel::clear).is_some()
+        };
+        drop(schemas);
+        if !removed {
+            return reply::Payload::Ack(ReplyAckCommand {});
+        }
+        info!(
+            "{} channel {}.",
+            if deleted { "Deleted" } else { "Cleared" },
+            channel
+        );
+
+        if let Some(persistence) = &state.persistence
+            && let Err(err) = persistence.record_removal(channel, deleted)
+        {
+            error!("Failed to persist removal of channel {}: {}", channel, err);
+        }
+
+        let message = Publish {
+            payload: Some(publish::Payload::Removal(PublishRemovalCommand {
+                channel: channel.to_string(),
+                deleted,
+            })),
+        }
+        .encode_to_vec();
+        let pub_socket = pub_socket.lock().unwrap();
+        if let Err(err) = pub_socket
+            .send(topic::REMOVAL_TOPIC, SNDMORE)
+            .and_then(|_| pub_socket.send(message, 0))
+        {
+            error!("Failed to publish removal of channel {}: {}", channel, err);
+        }
+
+        reply::Payload::Ack(ReplyAckCommand {})
+    }
+
+    fn log_publish(channel: &str, data: &supported_values::Kind) {
+        match data {
+            supported_values::Kind::Int64(data) => {
+                info!("Publishing Int64 data on channel {}: {}", channel, data)
+            }
+            supported_values::Kind::Int32(data) => {
+                info!("Publishing Int32 data on channel {}: {}", channel, data)
+            }
+            supported_values::Kind::Uint32(data) => {
+                info!("Publishing Uint32 data on channel {}: {}", channel, data)
+            }
+            supported_values::Kind::Uint64(data) => {
+                info!("Publishing Uint64 data on channel {}: {}", channel, data)
+            }
+            supported_values::Kind::Bool(data) => {
+                info!("Publishing Bool data on channel {}: {}", channel, data)
+            }
+            supported_values::Kind::Double(data) => {
+                info!("Publishing Double data on channel {}: {}", channel, data)
+            }
+            supported_values::Kind::Float(data) => {
+                info!("Publishing Float data on channel {}: {}", channel, data)
+            }
+            supported_values::Kind::String(data) => {
+                info!("Publishing String data on channel {}: {}", channel, data)
+            }
+            supported_values::Kind::Bytes(_) => {
+                info!("Publishing bytes data on channel {}", channel)
+            }
+            supported_values::Kind::StructValue(data) => {
+                info!(
+                    "Publishing {} data on channel {}: {}",
+                    data.type_name, channel, data.json
+                )
+            }
+            supported_values::Kind::DoubleArray(data) => {
+                info!(
+                    "Publishing Double[] data on channel {}: {}",
+                    channel,
+                    Self::summarize(&data.values)
+                )
+            }
+            supported_values::Kind::FloatArray(data) => {
+                info!(
+                    "Publishing Float[] data on channel {}: {}",
+                    channel,
+                    Self::summarize(&data.values)
+                )
+            }
+            supported_values::Kind::Int64Array(data) => {
+                info!(
+                    "Publishing Int64[] data on channel {}: {}",
+                    channel,
+                    Self::summarize(&data.values)
+                )
+            }
+            supported_values::Kind::BoolArray(data) => {
+                info!(
+                    "Publishing Bool[] data on channel {}: {}",
+                    channel,
+                    Self::summarize(&data.values)
+                )
+            }
+            supported_values::Kind::StringArray(data) => {
+                info!(
+                    "Publishing String[] data on channel {}: {}",
+                    channel,
+                    Self::summarize(&data.values)
+                )
+            }
+            supported_values::Kind::MapValue(_) => {
+                info!(
+                    "Publishing Map data on channel {}: {}",
+                    channel,
+                    Self::describe(data)
+                )
+            }
+        }
+    }
+
+    /// Readable form of a value, used for the fields of map values.
+    fn describe(data: &supported_values::Kind) -> String {
+        match data {
+            supported_values::Kind::String(data) => format!("{:?}", data),
+            supported_values::Kind::Int32(data) => data.to_string(),
+            supported_values::Kind::Int64(data) => data.to_string(),
+            supported_values::Kind::Uint32(data) => data.to_string(),
+            supported_values::Kind::Uint64(data) => data.to_string(),
+            supported_values::Kind::Bool(data) => data.to_string(),
+            supported_values::Kind::Double(data) => format!("{:?}", data),
+            supported_values::Kind::Float(data) => format!("{:?}", data),
+            supported_values::Kind::Bytes(data) => format!("<{} bytes>", data.len()),
+            supported_values::Kind::StructValue(data) => data.json.clone(),
+            supported_values::Kind::DoubleArray(data) => Self::summarize(&data.values),
+            supported_values::Kind::FloatArray(data) => Self::summarize(&data.values),
+            supported_values::Kind::Int64Array(data) => Self::summarize(&data.values),
+            supported_values::Kind::BoolArray(data) => Self::summarize(&data.values),
+            supported_values::Kind::StringArray(data) => Self::summarize(&data.values),
+            supported_values::Kind::MapValue(data) => {
+                let fields: Vec<String> = data
+                    .fields
+                    .iter()
+                    .map(|(name, value)| match &value.kind {
+                        Some(kind) => format!("{}: {}", name, Self::describe(kind)),
+                        None => format!("{}: ?", name),
+                    })
+                    .collect();
+                format!("{{{}}}", fields.join(", "))
+            }
+        }
+    }
+
+    /// The first few values of an array and how many there are, so large arrays do not flood
+    /// the log.
+    fn summarize<T: std::fmt::Debug>(values: &[T]) -> String {
+        const SHOWN: usize = 8;
+        if values.len() <= SHOWN {
+            return format!("{:?}", values);
+        }
+        let shown: Vec<String> = values[..SHOWN]
+            .iter()
+            .map(|value| format!("{:?}", value))
+            .collect();
+        format!("[{}, ...] ({} values)", shown.join(", "), values.len())
+    }
+
+    fn reject(rejected_messages: &AtomicU64, source: &str, err: &XTablesError) {
+        let rejected = rejected_messages.fetch_add(1, Ordering::SeqCst) + 1;
+        warn!(
+            "Rejected {} message: {} ({} rejected so far)",
+            source, err, rejected
+        );
+    }
+
+    /// Logs the number of rejected messages if it grew since `reported`, which it updates.
+    fn report_rejections(state: &ServerState, reported: &mut u64) {
+        let rejected = state.rejected_messages.load(Ordering::SeqCst);
+        if rejected > *reported {
+            warn!(
+                "{} messages rejected in the last {} seconds, {} since the server started ({} type mismatches).",
+                rejected - *reported,
+                REJECTION_REPORT_INTERVAL.as_secs(),
+                rejected,
+                state.type_mismatches.load(Ordering::SeqCst)
+            );
+            *reported = rejected;
+        }
+    }
+
+    /// Number of malformed pushes and requests the server has skipped since it was created.
+    pub fn rejected_messages(&self) -> u64 {
+        self.state.rejected_messages.load(Ordering::SeqCst)
+    }
+
+    /// How many values were rejected for not matching their channel's type, these also count
+    /// towards `rejected_messages`.
+    pub fn type_mismatches(&self) -> u64 {
+        self.state.type_mismatches.load(Ordering::SeqCst)
+    }
+
+    /// Adds or replaces a retention rule and applies it to the channels it matches.
+    pub fn set_retention(&self, rule: RetentionRule) -> Result<(), XTablesError> {
+        Self::apply_retention(&self.state, rule)
+    }
+
+    fn apply_retention(state: &ServerState, rule: RetentionRule) -> Result<(), XTablesError> {
+        let mut retention = state.retention.lock().unwrap();
+        retention.set(rule.clone())?;
+
+        let mut channels = state.cached_messages.lock().unwrap();
+        for (name, channel) in channels.iter_mut().filter(|(name, _)| rule.matches(name)) {
+            channel.set_policy(retention.policy_for(name));
+        }
+        info!("Retention of '{}' set to {:?}.", rule.pattern, rule.policy);
+        Ok(())
+    }
+
+    fn handle_set_retention(
+        state: &ServerState,
+        command: SetRetentionCommand,
+    ) -> Result<(), XTablesError> {
+        let policy = match command.policy.and_then(|policy| policy.policy) {
+            Some(retention_policy::Policy::Count(count)) => RetentionPolicy::Count(count as usize),
+            Some(retention_policy::Policy::WindowMs(window)) => RetentionPolicy::WindowMs(window),
+            Some(retention_policy::Policy::Latest(_)) => RetentionPolicy::Latest,
+            None => return Err(XTablesError::Malformed("retention request without policy")),
+        };
+        Self::apply_retention(state, RetentionRule::new(&command.pattern, policy)?)
+    }
+
+    fn handle_push(
+        state: &ServerState,
+        pub_socket: &Mutex<zmq::Socket>,
+        bytes: Vec<u8>,
+    ) -> Result<(), XTablesError> {
+        let push_request = Push::decode(Cursor::new(bytes))?;
+        let payload = push_request
+            .payload
+            .ok_or(XTablesError::Malformed("push without payload"))?;
+
+        match payload {
+            push::Payload::Send(command) => {
+                let channel = command.channel;
+                let data = command
+                    .value
+                    .and_then(|value| value.kind)
+                    .ok_or(XTablesError::Malformed("push without value"))?;
+                if !topic::is_valid_channel(&channel) {
+                    return Err(XTablesError::Malformed("invalid channel name"));
+                }
+
+                Self::write(
+                    state,
+                    pub_socket,
+                    &channel,
+                    command.source_timestamp,
+                    |_| Ok(Some(data)),
+                )?;
+            }
+            push::Payload::Batch(command) => Self::handle_batch(state, pub_socket, command)?,
+        }
+
+        Ok(())
+    }
+
+    /// Stores every value of the batch under a single hold of the locks and publishes them as
+    /// one message. A malformed value or type mismatch rejects the whole batch.
+    fn handle_batch(
+        state: &ServerState,
+        pub_socket: &Mutex<zmq::Socket>,
+        command: SendBatchCommand,
+    ) -> Result<(), XTablesError> {
+        let mut channel_names = HashSet::new();
+        let mut values = Vec::with_capacity(command.values.len());
+        for command in command.values {
+            let data = command
+                .value
+                .and_then(|value| value.kind)
+                .ok_or(XTablesError::Malformed("batch value without value"))?;
+            if !topic::is_valid_channel(&command.channel) {
+                return Err(XTablesError::Malformed("invalid channel name"));
+            }
+            if !channel_names.insert(command.channel.clone()) {
+                return Err(XTablesError::Malformed("channel set twice in one batch"));
+            }
+            values.push((command.channel, data, command.source_timestamp));
+        }
+        if values.is_empty() {
+            return Ok(());
+        }
+
+        let retention = state.retention.lock().unwrap();
+        let mut schemas = state.schemas.lock().unwrap();
+        let mut channels = state.cached_messages.lock().unwrap();
+
+        for (channel, data, _) in &values {
+            if let Err(err) = schemas.verify(channel, data) {
+                state.type_mismatches.fetch_add(1, Ordering::SeqCst);
+                return Err(err);
+            }
+        }
+        for (channel, data, _) in &values {
+            schemas.check(channel, data)?;
+        }
+        drop(schemas);
+
+        let batch = state.batches.fetch_add(1, Ordering::SeqCst) + 1;
+        let now = channel::now_micros();
+        info!("Publishing batch {} of {} values.", batch, values.len());
+        let values = values
+            .into_iter()
+            .map(|(channel, data, source_timestamp)| {
+                let stored = Self::store(
+                    state,
+                    &mut channels,
+                    &retention,
+                    &channel,
+                    data,
+                    source_timestamp,
+                    now,
+                );
+                Self::data_command(&channel, stored)
+            })
+            .collect::<Vec<_>>();
+
+        let pub_socket = pub_socket.lock().unwrap();
+        // each value also goes out under its channel's topic, for clients that do not know
+        // batches and for plain listeners
+        for value in &values {
+            let message = Publish {
+                payload: Some(publish::Payload::Data(value.clone())),
+            }
+            .encode_to_vec();
+            pub_socket.send(&value.channel, SNDMORE)?;
+            pub_socket.send(message, 0)?;
+        }
+        let message = Publish {
+            payload: Some(publish::Payload::Batch(PublishBatchCommand {
+                batch,
+                values,
+            })),
+        }
+        .encode_to_vec();
+        pub_socket.send(topic::BATCH_TOPIC, SNDMORE)?;
+        pub_socket.send(message, 0)?;
+
+        Ok(())
+    }
+
+    /// Appends `data` to `channel`, creating the channel under its retention policy, and
+    /// records it for persistence.
+    fn store<'a>(
+        state: &ServerState,
+        channels: &'a mut HashMap<String, Channel>,
+        retention: &RetentionPolicies,
+        channel: &str,
+        data: supported_values::Kind,
+        source_timestamp: Option<u64>,
+        server_timestamp: u64,
+    ) -> &'a StoredValue {
+        let entry = channels
+            .entry(channel.to_string())
+            .or_insert_with(|| Channel::new(retention.policy_for(channel)));
+
+        Self::log_publish(channel, &data);
+        let stored = entry.push_at(data, source_timestamp, server_timestamp);
+        if let Some(persistence) = &state.persistence
+            && let Err(err) = persistence.record(channel, stored)
+        {
+            error!("Failed to persist push on channel {}: {}", channel, err);
+        }
+        stored
+    }
+
+    /// Stores and publishes the value `update` computes from the latest value of `channel`,
+    /// all under the channel lock so concurrent writers cannot interleave. `update` returns
+    /// `None` to leave the channel as it is. Returns whether a value was written and the
+    /// latest value afterwards.
+    fn write<F>(
+        state: &ServerState,
+        pub_socket: &Mutex<zmq::Socket>,
+        channel: &str,
+        source_timestamp: Option<u64>,
+        update: F,
+    ) -> Result<(bool, Option<StoredValue>), XTablesError>
+    where
+        F: FnOnce(Option<&StoredValue>) -> Result<Option<supported_values::Kind>, XTablesError>,
+    {
+        let retention = state.retention.lock().unwrap();
+        let mut schemas = state.schemas.lock().unwrap();
+        let mut channels = state.cached_messages.lock().unwrap();
+
+        let latest = channels.get(channel).and_then(Channel::latest);
+        let Some(data) = update(latest)? else {
+            return Ok((false, latest.cloned()));
+        };
+        if let Err(err) = schemas.check(channel, &data) {
+            state.type_mismatches.fetch_add(1, Ordering::SeqCst);
+            return Err(err);
+        }
+        drop(schemas);
+
+        let stored = Self::store(
+            state,
+            &mut channels,
+            &retention,
+            channel,
+            data,
+            source_timestamp,
+            channel::now_micros(),
+        );
+        let message = Self::publish_data(channel, stored);
+
+        let pub_socket = pub_socket.lock().unwrap();
+        pub_socket.send(channel, SNDMORE)?;
+        pub_socket.send(message, 0)?;
+
+        Ok((true, Some(stored.clone())))
+    }
+
+    /// Runs a compare-and-set, add, toggle or acknowledged set request through `write` and
+    /// replies with its outcome.
+    fn handle_atomic<F>(
+        state: &ServerState,
+        pub_socket: &Mutex<zmq::Socket>,
+        channel: &str,
+        source_timestamp: Option<u64>,
+        update: F,
+    ) -> reply::Payload
+    where
+        F: FnOnce(Option<&StoredValue>) -> Result<Option<supported_values::Kind>, XTablesError>,
+    {
+        if !topic::is_valid_channel(channel) {
+            return reply::Payload::Error(ReplyErrorCommand {
+                message: format!("invalid channel name '{}'", channel),
+            });
+        }
+        match Self::write(state, pub_socket, channel, source_timestamp, update) {
+            Ok((applied, current)) => reply::Payload::Write(ReplyWriteCommand {
+                applied,
+                current: current.as_ref().map(StoredValue::to_stamped),
+            }),
+            Err(err) => {
+                warn!("Rejected write to channel {}: {}", channel, err);
+                reply::Payload::Error(ReplyErrorCommand {
+                    message: err.to_string(),
+                })
+            }
+        }
+    }
+
+    /// Spawns the worker threads. After a `stop` the sockets are bound again, which fails if
+    /// another process took one of the ports in the meantime.
+    pub fn start(&self) -> Result<(), XTablesError> {
+        let mut workers = self.workers.lock().unwrap();
+        if !workers.is_empty() {
             info!("XTables server is already running.");
-            return;
+            return Ok(());
         }
 
+        let sockets = match self.sockets.lock().unwrap().take() {
+            Some(sockets) => {
+                info!("Initializing XTables server...");
+                sockets
+            }
+            None => {
+                info!("Starting XTables server...");
+                self.rebind_sockets()?
+            }
+        };
+        self.stop.store(false, Ordering::SeqCst);
+
+        let pub_socket = Arc::new(Mutex::new(sockets.pub_socket));
+
         {
-            let cached_messages = self.cached_messages.clone();
-            let pull_socket = self.pull_socket.clone();
-            let pub_socket = self.pub_socket.clone();
+            let state = self.state.clone();
+            let pull_socket = sockets.pull_socket;
+            let pub_socket = pub_socket.clone();
             let stop: Arc<AtomicBool> = self.stop.clone();
 
-            task::spawn_blocking(move || {
-                let pull_socket = pull_socket.lock().unwrap();
+            workers.push(Self::spawn_worker("xtables-pull", move || {
                 loop {
                     if stop.load(Ordering::SeqCst) {
                         break;
                     }
-                    let bytes = pull_socket.recv_bytes(0).unwrap();
-
-                    let push_request = Push::decode(Cursor::new(bytes)).unwrap();
-                    let payload = push_request.payload.unwrap();
-
-                    match payload {
-                        push::Payload::Send(command) => {
-                            let channel = command.channel;
-                            let data = command.value.unwrap().kind.unwrap();
-                            let mut ring_buffer = cached_messages.lock().unwrap();
-                            let ring_buffer = ring_buffer
-                                .entry(channel.clone())
-                                .or_insert(RingBuffer::new(100));
-
-                            let pub_socket = pub_socket.lock().unwrap();
-
-                            match data {
-                                supported_values::Kind::Int64(data) => {
-                                    let message = Self::publish_data(
-                                        &channel,
-                                        supported_values::Kind::Int64(data),
-                                    );
-                                    ring_buffer.push(supported_values::Kind::Int64(data));
-                                    info!("Publishing Int64 data on channel {}: {}", channel, data);
-                                    pub_socket.send(&channel, SNDMORE).unwrap();
-                                    pub_socket.send(message, 0).unwrap();
-                                }
-                                supported_values::Kind::Int32(data) => {
-                                    let message = Self::publish_data(
-                                        &channel,
-                                        supported_values::Kind::Int32(data),
-                                    );
-                                    info!("Publishing Int32 data on channel {}: {}", channel, data);
-                                    ring_buffer.push(supported_values::Kind::Int32(data));
-                                    pub_socket.send(&channel, SNDMORE).unwrap();
-                                    pub_socket.send(message, 0).unwrap();
-                                }
-                                supported_values::Kind::Uint32(data) => {
-                                    let message = Self::publish_data(
-                                        &channel,
-                                        supported_values::Kind::Uint32(data),
-                                    );
-                                    info!(
-                                        "Publishing Uint32 data on channel {}: {}",
-                                        channel, data
-                                    );
-                                    ring_buffer.push(supported_values::Kind::Uint32(data));
-                                    pub_socket.send(&channel, SNDMORE).unwrap();
-                                    pub_socket.send(message, 0).unwrap();
-                                }
-                                supported_values::Kind::Uint64(data) => {
-                                    let message = Self::publish_data(
-                                        &channel,
-                                        supported_values::Kind::Uint64(data),
-                                    );
-                                    info!(
-                                        "Publishing Uint64 data on channel {}: {}",
-                                        channel, data
-                                    );
-                                    ring_buffer.push(supported_values::Kind::Uint64(data));
-                                    pub_socket.send(&channel, SNDMORE).unwrap();
-                                    pub_socket.send(message, 0).unwrap();
-                                }
-                                supported_values::Kind::Bool(data) => {
-                                    let message = Self::publish_data(
-                                        &channel,
-                                        supported_values::Kind::Bool(data),
-                                    );
-                                    info!("Publishing Bool data on channel {}: {}", channel, data);
-                                    ring_buffer.push(supported_values::Kind::Bool(data));
-                                    pub_socket.send(&channel, SNDMORE).unwrap();
-                                    pub_socket.send(message, 0).unwrap();
-                                }
-                                supported_values::Kind::Double(data) => {
-                                    let message = Self::publish_data(
-                                        &channel,
-                                        supported_values::Kind::Double(data),
-                                    );
-                                    info!(
-                                        "Publishing Double data on channel {}: {}",
-                                        channel, data
-                                    );
-                                    ring_buffer.push(supported_values::Kind::Double(data));
-                                    pub_socket.send(&channel, SNDMORE).unwrap();
-                                    pub_socket.send(message, 0).unwrap();
-                                }
-                                supported_values::Kind::Float(data) => {
-                                    let message = Self::publish_data(
-                                        &channel,
-                                        supported_values::Kind::Float(data),
-                                    );
-                                    info!("Publishing Float data on channel {}: {}", channel, data);
-                                    ring_buffer.push(supported_values::Kind::Float(data));
-                                    pub_socket.send(&channel, SNDMORE).unwrap();
-                                    pub_socket.send(message, 0).unwrap();
-                                }
-                                supported_values::Kind::String(data) => {
-                                    let message = Self::publish_data(
-                                        &channel,
-                                        supported_values::Kind::String(data.clone()),
-                                    );
-                                    info!(
-                                        "Publishing String data on channel {}: {}",
-                                        channel, data
-                                    );
-                                    ring_buffer.push(supported_values::Kind::String(data));
-                                    pub_socket.send(&channel, SNDMORE).unwrap();
-                                    pub_socket.send(message, 0).unwrap();
-                                }
-                                supported_values::Kind::Bytes(data) => {
-                                    let message = Self::publish_data(
-                                        &channel,
-                                        supported_values::Kind::Bytes(data.clone()),
-                                    );
-                                    info!("Publishing bytes data on channel {}", channel);
-                                    ring_buffer.push(supported_values::Kind::Bytes(data));
-                                    pub_socket.send(&channel, SNDMORE).unwrap();
-                                    pub_socket.send(message, 0).unwrap();
-                                }
-                            }
+                    if !Self::wait_readable(&pull_socket) {
+                        continue;
+                    }
+                    let bytes = match pull_socket.recv_bytes(0) {
+                        Ok(bytes) => bytes,
+                        Err(err) => {
+                            error!("Failed to receive push: {}", err);
+                            continue;
                         }
+                    };
+
+                    match Self::handle_push(&state, &pub_socket, bytes) {
+                        Ok(()) => {}
+                        Err(XTablesError::Zmq(err)) => error!("Failed to publish push: {}", err),
+                        Err(err) => Self::reject(&state.rejected_messages, "push", &err),
                     }
                 }
-            });
+            }));
         }
 
         {
-            let pub_socket = self.pub_socket.clone();
+            let pub_socket = pub_socket.clone();
             let stop = self.stop.clone();
 
-            task::spawn_blocking(move || {
+            workers.push(Self::spawn_worker("xtables-logs", move || {
                 loop {
                     if stop.load(Ordering::SeqCst) {
                         break;
                     }
-                    let logs = LOGGER.read_unread_logs();
+                    let logs =
+                        LOGGER.wait_unread_logs(Duration::from_millis(POLL_TIMEOUT_MS as u64));
                     if let Some(logs) = logs {
                         let value = Publish {
                             payload: Some(publish::Payload::Logs(SendLogsCommand { logs })),
                         }
                         .encode_to_vec();
-                        pub_socket
-                            .lock()
-                            .unwrap()
-                            .send("XTABLES_INTERNAL_LOG", SNDMORE)
-                            .unwrap();
-                        pub_socket.lock().unwrap().send(value, 0).unwrap();
+                        let pub_socket = pub_socket.lock().unwrap();
+                        // logging the failure would feed this loop another log line to publish
+                        let _ = pub_socket
+                            .send(topic::LOG_TOPIC, SNDMORE)
+                            .and_then(|_| pub_socket.send(value, 0));
                     }
                 }
-            });
+            }));
         }
 
         {
-            let cached_buffers = self.cached_messages.clone();
-            let rep_socket = self.rep_socket.clone();
+            let state = self.state.clone();
+            let router_socket = sockets.router_socket;
+            let pub_socket = pub_socket.clone();
             let stop = self.stop.clone();
 
-            task::spawn_blocking(move || {
-                let rep_socket = rep_socket.lock().unwrap();
+            workers.push(Self::spawn_worker("xtables-router", move || {
                 loop {
                     if stop.load(Ordering::SeqCst) {
                         break;
                     }
+                    if !Self::wait_readable(&router_socket) {
+                        continue;
+                    }
 
-                    let bytes = rep_socket.recv_bytes(0).unwrap();
-
-                    let request = Request::decode(Cursor::new(bytes)).unwrap();
-                    let payload = request.payload.unwrap();
-
-                    match payload {
-                        request::Payload::Data(command) => {
-                            let channel = command.channel;
-                            let mut ring_buffer = cached_buffers.lock().unwrap();
-                            let ring_buffer = ring_buffer
-                                .entry(channel)
-                                .or_insert_with(|| RingBuffer::new(100));
-
-                            let data: supported_values::Kind = ring_buffer
-                                .peek()
-                                .unwrap_or(&supported_values::Kind::String(String::from(
-                                    "XTABLES_INTERNAL_NO_DATA_AVAILABLE",
-                                )))
-                                .clone();
-
-                            let message = Reply {
-                                payload: Some(reply::Payload::Data(ReplyDataCommand {
-                                    value: Some(SupportedValues { kind: Some(data) }),
-                                })),
-                            }
-                            .encode_to_vec();
-
-                            rep_socket.send(message, 0).unwrap();
+                    let frames = match router_socket.recv_multipart(0) {
+                        Ok(frames) => frames,
+                        Err(err) => {
+                            error!("Failed to receive request: {}", err);
+                            continue;
                         }
-                        request::Payload::Logs(_) => {
-                            let logs = LOGGER.get_logs();
-                            if let Some(logs) = logs {
-                                info!("Sending logs in response to request.");
-                                let message = Reply {
-                                    payload: Some(reply::Payload::Logs(ReplyLogsCommand { logs })),
-                                }
-                                .encode_to_vec();
-
-                                rep_socket.send(message, 0).unwrap();
-                            } else {
-                                let message = Reply {
-                                    payload: Some(reply::Payload::Logs(ReplyLogsCommand {
-                                        logs: vec![],
-                                    })),
-                                }
-                                .encode_to_vec();
-
-                                rep_socket.send(message, 0).unwrap();
-                            }
+                    };
+                    let Some((envelope, bytes)) = Self::split_envelope(frames) else {
+                        let err = XTablesError::Malformed("unexpected number of frames");
+                        Self::reject(&state.rejected_messages, "request", &err);
+                        continue;
+                    };
+
+                    let request = match Request::decode(Cursor::new(bytes)) {
+                        Ok(request) => request,
+                        Err(err) => {
+                            Self::reject(&state.rejected_messages, "request", &err.into());
+                            continue;
+                        }
+                    };
+
+                    let payload = match request.payload {
+                        Some(payload) => Self::handle_request(
+                            &state,
+                            &pub_socket,
+                            payload,
+                            &request.admin_token,
+                            request.protocol_version,
+                        ),
+                        None => {
+                            let err = XTablesError::Malformed("request without payload");
+                            Self::reject(&state.rejected_messages, "request", &err);
+                            reply::Payload::Error(ReplyErrorCommand {
+                                message: err.to_string(),
+                            })
                         }
+                    };
+
+                    let message = Reply {
+                        id: request.id,
+                        protocol_version: PROTOCOL_VERSION,
+                        payload: Some(payload),
+                    }
+                    .encode_to_vec();
+
+                    // ROUTER drops replies to peers that have gone away instead of blocking.
+                    if let Err(err) = router_socket
+                        .send_multipart(envelope, SNDMORE)
+                        .and_then(|_| router_socket.send(message, 0))
+                    {
+                        error!("Failed to send reply: {}", err);
                     }
                 }
-            });
+            }));
+        }
+
+        {
+            let state = self.state.clone();
+            let pub_socket = pub_socket.clone();
+            let stop = self.stop.clone();
+            let message = Publish {
+                payload: Some(publish::Payload::Heartbeat(HeartbeatCommand {
+                    instance: channel::now_micros(),
+                    interval_ms: HEARTBEAT_INTERVAL.as_millis() as u32,
+                })),
+            }
+            .encode_to_vec();
+
+            workers.push(Self::spawn_worker("xtables-heartbeat", move || {
+                let mut last_heartbeat: Option<Instant> = None;
+                // rejections are reported from this loop too rather than from a thread of their own
+                let mut last_report = Instant::now();
+                let mut reported = state.rejected_messages.load(Ordering::SeqCst);
+                loop {
+                    if stop.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    if last_report.elapsed() >= REJECTION_REPORT_INTERVAL {
+                        Self::report_rejections(&state, &mut reported);
+                        last_report = Instant::now();
+                    }
+                    if last_heartbeat.is_some_and(|sent| sent.elapsed() < HEARTBEAT_INTERVAL) {
+                        thread::sleep(Duration::from_millis(POLL_TIMEOUT_MS as u64));
+                        continue;
+                    }
+                    let pub_socket = pub_socket.lock().unwrap();
+                    if let Err(err) = pub_socket
+                        .send(topic::HEARTBEAT_TOPIC, SNDMORE)
+                        .and_then(|_| pub_socket.send(&message[..], 0))
+                    {
+                        error!("Failed to publish heartbeat: {}", err);
+                    }
+                    last_heartbeat = Some(Instant::now());
+                }
+            }));
+        }
+
+        if self.state.persistence.is_some() {
+            let state = self.state.clone();
+            let (wakeup, wakeups) = mpsc::channel::<()>();
+            *self.persist_wakeup.lock().unwrap() = Some(wakeup);
+
+            workers.push(Self::spawn_worker("xtables-persist", move || {
+                let Some(persistence) = &state.persistence else {
+                    return;
+                };
+                let mut last_snapshot = Instant::now();
+                // ends once `stop` drops the sender
+                while let Err(RecvTimeoutError::Timeout) =
+                    wakeups.recv_timeout(persistence.config().sync_interval())
+                {
+                    if let Err(err) = persistence.sync() {
+                        error!("Failed to sync write-ahead log: {}", err);
+                    }
+                    if last_snapshot.elapsed() >= persistence.config().snapshot_interval() {
+                        if let Err(err) = persistence.snapshot(&state.cached_messages) {
+                            error!("Failed to snapshot channels: {}", err);
+                        }
+                        last_snapshot = Instant::now();
+                    }
+                }
+            }));
         }
+
+        Ok(())
     }
 
+    /// Writes the persisted channels to disk now instead of waiting for the next snapshot.
+    /// Does nothing unless the server was built with persistence.
+    pub fn snapshot(&self) -> Result<(), XTablesError> {
+        match &self.state.persistence {
+            Some(persistence) => persistence.snapshot(&self.state.cached_messages),
+            None => Ok(()),
+        }
+    }
+
+    /// Splits a request into the envelope its reply is sent back with and the request itself.
+    /// ROUTER prefixes every message with the identity of its sender. DEALER clients send the
+    /// request right after it, REQ clients of older versions put an empty delimiter frame in
+    /// between, which the reply has to carry as well.
+    fn split_envelope(mut frames: Vec<Vec<u8>>) -> Option<(Vec<Vec<u8>>, Vec<u8>)> {
+        let bytes = frames.pop()?;
+        match frames.as_slice() {
+            [_] => Some((frames, bytes)),
+            [_, delimiter] if delimiter.is_empty() => Some((frames, bytes)),
+            _ => None,
+        }
+    }
+
+    fn handle_request(
+        state: &ServerState,
+        pub_socket: &Mutex<zmq::Socket>,
+        payload: request::Payload,
+        admin_token: &str,
+        protocol_version: u32,
+    ) -> reply::Payload {
+        match payload {
+            request::Payload::Data(command) => {
+                let channels = state.cached_messages.lock().unwrap();
+
+                match channels.get(&command.channel).and_then(Channel::latest) {
+                    Some(data) => reply::Payload::Data(ReplyDataCommand {
+                        data: Some(reply_data_command::Data::Value(SupportedValues {
+                            kind: Some(data.kind.clone()),
+                        })),
+                        source_timestamp: data.source_timestamp,
+                        server_timestamp: data.server_timestamp,
+                        sequence: data.sequence,
+                    }),
+                    // clients that predate `not_found` only understand the sentinel
+                    None if protocol_version < 2 => reply::Payload::Data(ReplyDataCommand {
+                        data: Some(reply_data_command::Data::Value(SupportedValues {
+                            kind: Some(supported_values::Kind::String(LEGACY_NO_DATA.to_string())),
+                        })),
+                        ..Default::default()
+                    }),
+                    None => reply::Payload::Data(ReplyDataCommand {
+                        data: Some(reply_data_command::Data::NotFound(NotFound {})),
+                        ..Default::default()
+                    }),
+                }
+            }
+            request::Payload::History(command) => {
+                let query = match command.query {
+                    Some(get_history_command::Query::Last(count)) => {
+                        HistoryQuery::Last(count as usize)
+                    }
+                    Some(get_history_command::Query::SinceSequence(sequence)) => {
+                        HistoryQuery::SinceSequence(sequence)
+                    }
+                    Some(get_history_command::Query::SinceTimestamp(timestamp)) => {
+                        HistoryQuery::SinceTimestamp(timestamp)
+                    }
+                    None => {
+                        return reply::Payload::Error(ReplyErrorCommand {
+                            message: String::from("history request without query"),
+                        });
+                    }
+                };
+
+                let mut channels = state.cached_messages.lock().unwrap();
+                let values = channels
+                    .get_mut(&command.channel)
+                    .map(|channel| {
+                        channel
+                            .history(query)
+                            .into_iter()
+                            .map(StoredValue::to_stamped)
+                            .collect()
+                    })
+                    .unwrap_or_default();
+
+                reply::Payload::History(ReplyHistoryCommand { values })
+            }
+            request::Payload::Logs(_) => {
+                let logs = LOGGER.get_logs();
+                if let Some(logs) = logs {
+                    info!("Sending logs in response to request.");
+                    reply::Payload::Logs(ReplyLogsCommand { logs })
+                } else {
+                    reply::Payload::Logs(ReplyLogsCommand { logs: vec![] })
+                }
+            }
+            request::Payload::Channels(command) => {
+                let channels = state.cached_messages.lock().unwrap();
+                let mut channels: Vec<ChannelInfo> = channels
+                    .iter()
+                    .filter(|(name, _)| name.starts_with(&command.prefix))
+                    .map(|(name, channel)| ChannelInfo {
+                        channel: name.clone(),
+                        value_type: channel
+                            .latest()
+                            .map(|data| data.kind.type_name().to_string()),
+                        last_update: channel.latest().map(|data| data.server_timestamp),
+                        update_count: channel.update_count(),
+                    })
+                    .collect();
+                channels.sort_by(|a, b| a.channel.cmp(&b.channel));
+
+                reply::Payload::Channels(ReplyChannelsCommand { channels })
+            }
+            request::Payload::Schemas(command) => {
+                let schemas = state.schemas.lock().unwrap();
+                let mut schemas: Vec<ChannelSchema> = schemas
+                    .iter()
+                    .filter(|(name, _)| name.starts_with(&command.prefix))
+                    .map(|(name, schema)| ChannelSchema {
+                        channel: name.to_string(),
+                        value_type: schema.value_type.clone(),
+                        declared: schema.declared,
+                    })
+                    .collect();
+                schemas.sort_by(|a, b| a.channel.cmp(&b.channel));
+
+                reply::Payload::Schemas(ReplySchemasCommand { schemas })
+            }
+            request::Payload::Declare(command) => {
+                if state.admin_token.as_deref() != Some(admin_token) {
+                    warn!("Refused to declare a channel type without a valid admin token.");
+                    return reply::Payload::Error(ReplyErrorCommand {
+                        message: String::from("declaring channel types requires the admin token"),
+                    });
+                }
+                if !topic::is_valid_channel(&command.channel) {
+                    return reply::Payload::Error(ReplyErrorCommand {
+                        message: format!("invalid channel name '{}'", command.channel),
+                    });
+                }
+                let result = state
+                    .schemas
+                    .lock()
+                    .unwrap()
+                    .declare(&command.channel, &command.value_type);
+                match result {
+                    Ok(()) => {
+                        info!(
+                            "Declared channel {} as {}.",
+                            command.channel, command.value_type
+                        );
+                        reply::Payload::Ack(ReplyAckCommand {})
+                    }
+                    Err(err) => reply::Payload::Error(ReplyErrorCommand {
+                        message: err.to_string(),
+                    }),
+                }
+            }
+            request::Payload::CompareAndSet(command) => {
+                let Some(value) = command.value.and_then(|value| value.kind) else {
+                    return reply::Payload::Error(ReplyErrorCommand {
+                        message: String::from("compare-and-set without value"),
+                    });
+                };
+                Self::handle_atomic(state, pub_socket, &command.channel, None, |latest| {
+                    let matches = match command.expected {
+                        Some(compare_and_set_command::Expected::ExpectedValue(expected)) => {
+                            expected.kind.as_ref() == latest.map(|latest| &latest.kind)
+                        }
+                        Some(compare_and_set_command::Expected::ExpectedSequence(sequence)) => {
+                            latest.map_or(0, |latest| latest.sequence) == sequence
+                        }
+                        None => {
+                            return Err(XTablesError::Malformed(
+                                "compare-and-set without expectation",
+                            ));
+                        }
+                    };
+                    Ok(matches.then_some(value))
+                })
+            }
+            request::Payload::Add(command) => {
+                let Some(delta) = command.delta.and_then(|delta| delta.kind) else {
+                    return reply::Payload::Error(ReplyErrorCommand {
+                        message: String::from("add without delta"),
+                    });
+                };
+                Self::handle_atomic(state, pub_socket, &command.channel, None, |latest| {
+                    channel::add(latest.map(|latest| &latest.kind), delta).map(Some)
+                })
+            }
+            request::Payload::Toggle(command) => {
+                Self::handle_atomic(state, pub_socket, &command.channel, None, |latest| {
+                    channel::toggle(latest.map(|latest| &latest.kind)).map(Some)
+                })
+            }
+            request::Payload::Set(command) => {
+                let Some(value) = command.value.and_then(|value| value.kind) else {
+                    return reply::Payload::Error(ReplyErrorCommand {
+                        message: String::from("set without value"),
+                    });
+                };
+                Self::handle_atomic(
+                    state,
+                    pub_socket,
+                    &command.channel,
+                    command.source_timestamp,
+                    |_| Ok(Some(value)),
+                )
+            }
+            request::Payload::Delete(command) => {
+                Self::handle_removal(state, pub_socket, &command.channel, true)
+            }
+            request::Payload::Clear(command) => {
+                Self::handle_removal(state, pub_socket, &command.channel, false)
+            }
+            request::Payload::Retention(command) => {
+                if state.admin_token.as_deref() != Some(admin_token) {
+                    warn!("Refused to change retention without a valid admin token.");
+                    return reply::Payload::Error(ReplyErrorCommand {
+                        message: String::from("changing retention requires the admin token"),
+                    });
+                }
+                match Self::handle_set_retention(state, command) {
+                    Ok(()) => reply::Payload::Ack(ReplyAckCommand {}),
+                    Err(err) => reply::Payload::Error(ReplyErrorCommand {
+                        message: err.to_string(),
+                    }),
+                }
+            }
+        }
+    }
+
+    /// Signals the worker threads and waits for them to exit, closing every socket.
     pub fn stop(&self) {
+        let workers: Vec<JoinHandle<()>> = self.workers.lock().unwrap().drain(..).collect();
+        if workers.is_empty() {
+            return;
+        }
+
         self.stop.store(true, Ordering::SeqCst);
+        self.persist_wakeup.lock().unwrap().take();
+        for worker in workers {
+            if worker.join().is_err() {
+                error!("An XTables worker thread panicked.");
+            }
+        }
+        if let Err(err) = self.snapshot() {
+            error!("Failed to snapshot channels on shutdown: {}", err);
+        }
         info!("XTables server has been stopped.");
     }
 }
 
+impl Drop for XTablesServer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
 impl Default for XTablesServer {
     fn default() -> Self {
         XTablesServer::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use xtables_protobuf::protobuf::{
+        ClearChannelCommand, CompareAndSetCommand, DeleteChannelCommand, GetDataCommand,
+        ListChannelsCommand, SendDataCommand,
+    };
+
+    use super::*;
+
+    fn state() -> ServerState {
+        ServerState {
+            cached_messages: Mutex::new(HashMap::new()),
+            retention: Mutex::new(RetentionPolicies::default()),
+            schemas: Mutex::new(SchemaRegistry::new()),
+            admin_token: None,
+            persistence: None,
+            rejected_messages: AtomicU64::new(0),
+            type_mismatches: AtomicU64::new(0),
+            batches: AtomicU64::new(0),
+        }
+    }
+
+    /// A PUB socket the handlers can publish to, bound to `endpoint` within `context`.
+    fn pub_socket(context: &Context, endpoint: &str) -> Mutex<zmq::Socket> {
+        let socket = context.socket(PUB).unwrap();
+        socket.bind(endpoint).unwrap();
+        Mutex::new(socket)
+    }
+
+    /// Stores `kind` as the next value of `channel` like a push would.
+    fn push(
+        state: &ServerState,
+        pub_socket: &Mutex<zmq::Socket>,
+        channel: &str,
+        kind: supported_values::Kind,
+    ) -> StoredValue {
+        let (_, stored) =
+            XTablesServer::write(state, pub_socket, channel, None, |_| Ok(Some(kind))).unwrap();
+        stored.unwrap()
+    }
+
+    fn batch(values: &[(&str, supported_values::Kind)]) -> SendBatchCommand {
+        SendBatchCommand {
+            values: values
+                .iter()
+                .map(|(channel, kind)| SendDataCommand {
+                    channel: channel.to_string(),
+                    value: Some(SupportedValues {
+                        kind: Some(kind.clone()),
+                    }),
+                    source_timestamp: None,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn publishes_batches_as_one_message() {
+        let context = Context::new();
+        let pub_socket = pub_socket(&context, "inproc://batches");
+        let sub_socket = context.socket(zmq::SUB).unwrap();
+        sub_socket.connect("inproc://batches").unwrap();
+        sub_socket
+            .set_subscribe(topic::BATCH_TOPIC.as_bytes())
+            .unwrap();
+        sub_socket.set_subscribe(b"vision/ty").unwrap();
+        let state = state();
+
+        let values = [
+            ("vision/tx", supported_values::Kind::Double(1.5)),
+            ("vision/ty", supported_values::Kind::Double(-0.5)),
+        ];
+        XTablesServer::handle_batch(&state, &pub_socket, batch(&values)).unwrap();
+
+        // clients without batch support still see each value under its channel's topic
+        let frames = sub_socket.recv_multipart(0).unwrap();
+        assert_eq!(frames[0], b"vision/ty");
+        let Some(publish::Payload::Data(published)) =
+            Publish::decode(&frames[1][..]).unwrap().payload
+        else {
+            panic!("expected a value");
+        };
+        assert_eq!(published.value.unwrap().kind, Some(values[1].1.clone()));
+
+        let frames = sub_socket.recv_multipart(0).unwrap();
+        assert_eq!(frames[0], topic::BATCH_TOPIC.as_bytes());
+        let Some(publish::Payload::Batch(published)) =
+            Publish::decode(&frames[1][..]).unwrap().payload
+        else {
+            panic!("expected a batch");
+        };
+        assert_eq!(published.batch, 1);
+        let channels: Vec<&str> = published
+            .values
+            .iter()
+            .map(|value| value.channel.as_str())
+            .collect();
+        assert_eq!(channels, ["vision/tx", "vision/ty"]);
+        assert_eq!(
+            published.values[0].server_timestamp,
+            published.values[1].server_timestamp
+        );
+
+        let channels = state.cached_messages.lock().unwrap();
+        assert_eq!(channels["vision/ty"].latest().unwrap().kind, values[1].1);
+    }
+
+    #[test]
+    fn rejects_whole_batch() {
+        let context = Context::new();
+        let pub_socket = pub_socket(&context, "inproc://rejected-batches");
+        let state = state();
+        state
+            .schemas
+            .lock()
+            .unwrap()
+            .declare("arm/angle", "double")
+            .unwrap();
+
+        let mismatch = batch(&[
+            ("drive/speed", supported_values::Kind::Double(2.0)),
+            (
+                "arm/angle",
+                supported_values::Kind::String(String::from("up")),
+            ),
+        ]);
+        assert!(XTablesServer::handle_batch(&state, &pub_socket, mismatch).is_err());
+        assert_eq!(state.type_mismatches.load(Ordering::SeqCst), 1);
+
+        let duplicate = batch(&[
+            ("drive/speed", supported_values::Kind::Double(2.0)),
+            ("drive/speed", supported_values::Kind::Double(3.0)),
+        ]);
+        assert!(matches!(
+            XTablesServer::handle_batch(&state, &pub_socket, duplicate),
+            Err(XTablesError::Malformed(_))
+        ));
+
+        // neither batch stored a value or locked the type of a channel
+        assert!(state.cached_messages.lock().unwrap().is_empty());
+        assert!(state.schemas.lock().unwrap().get("drive/speed").is_none());
+        assert_eq!(state.batches.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn missing_values_by_protocol_version() {
+        let context = Context::new();
+        let pub_socket = pub_socket(&context, "inproc://missing-values");
+        let state = state();
+        let get = |protocol_version| {
+            let payload = request::Payload::Data(GetDataCommand {
+                channel: String::from("drive/speed"),
+            });
+            match XTablesServer::handle_request(&state, &pub_socket, payload, "", protocol_version)
+            {
+                reply::Payload::Data(command) => command.data,
+                payload => panic!("unexpected reply {:?}", payload),
+            }
+        };
+
+        let sentinel = reply_data_command::Data::Value(SupportedValues {
+            kind: Some(supported_values::Kind::String(LEGACY_NO_DATA.to_string())),
+        });
+        assert_eq!(get(0), Some(sentinel.clone()));
+        assert_eq!(get(1), Some(sentinel));
+        assert_eq!(
+            get(PROTOCOL_VERSION),
+            Some(reply_data_command::Data::NotFound(NotFound {}))
+        );
+    }
+
+    #[test]
+    fn stop_does_not_wait_for_sync() {
+        let directory = env::temp_dir().join(format!("xtables-stop-{}", process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        let mut persistence = PersistenceConfig::new(directory.join("xtables.db"));
+        persistence.sync_interval_ms = 60_000;
+
+        let server = XTablesServer::builder()
+            .interface("127.0.0.1")
+            .pub_port(49611)
+            .pull_port(49612)
+            .rep_port(49613)
+            .persistence(persistence)
+            .build()
+            .unwrap();
+        server.start().unwrap();
+
+        let stopping = Instant::now();
+        server.stop();
+        assert!(stopping.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn skips_malformed_pushes() {
+        let server = XTablesServer::builder()
+            .interface("127.0.0.1")
+            .pub_port(49621)
+            .pull_port(49622)
+            .rep_port(49623)
+            .build()
+            .unwrap();
+        server.start().unwrap();
+        let context = Context::new();
+        let push_socket = context.socket(zmq::PUSH).unwrap();
+        push_socket.connect("tcp://127.0.0.1:49622").unwrap();
+
+        push_socket.send(&b"\xff\xff"[..], 0).unwrap();
+        let valid = Push {
+            payload: Some(push::Payload::Send(SendDataCommand {
+                channel: String::from("drive/speed"),
+                value: Some(SupportedValues {
+                    kind: Some(supported_values::Kind::Int32(3)),
+                }),
+                source_timestamp: None,
+            })),
+        };
+        push_socket.send(valid.encode_to_vec(), 0).unwrap();
+
+        // pushes are handled in order, so once the valid one is stored the other was seen
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let stored = loop {
+            let channels = server.state.cached_messages.lock().unwrap();
+            if let Some(latest) = channels.get("drive/speed").and_then(Channel::latest) {
+                break latest.kind.clone();
+            }
+            drop(channels);
+            assert!(Instant::now() < deadline, "valid push was not stored");
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(stored, supported_values::Kind::Int32(3));
+        assert_eq!(server.rejected_messages(), 1);
+        server.stop();
+    }
+
+    #[test]
+    fn lists_channels() {
+        let context = Context::new();
+        let pub_socket = pub_socket(&context, "inproc://channels");
+        let state = state();
+        push(
+            &state,
+            &pub_socket,
+            "drive/right",
+            supported_values::Kind::Double(1.0),
+        );
+        let right = push(
+            &state,
+            &pub_socket,
+            "drive/right",
+            supported_values::Kind::Double(2.0),
+        );
+        push(
+            &state,
+            &pub_socket,
+            "drive/left",
+            supported_values::Kind::Int32(3),
+        );
+        push(
+            &state,
+            &pub_socket,
+            "drivetrain",
+            supported_values::Kind::Bool(true),
+        );
+        push(
+            &state,
+            &pub_socket,
+            "arm/angle",
+            supported_values::Kind::Double(0.5),
+        );
+
+        let payload = request::Payload::Channels(ListChannelsCommand {
+            prefix: String::from("drive/"),
+        });
+        let reply::Payload::Channels(reply) =
+            XTablesServer::handle_request(&state, &pub_socket, payload, "", PROTOCOL_VERSION)
+        else {
+            panic!("expected channels");
+        };
+
+        let channels: Vec<&str> = reply
+            .channels
+            .iter()
+            .map(|info| info.channel.as_str())
+            .collect();
+        assert_eq!(channels, ["drive/left", "drive/right"]);
+        let right_info = &reply.channels[1];
+        assert_eq!(right_info.value_type.as_deref(), Some("double"));
+        assert_eq!(right_info.last_update, Some(right.server_timestamp));
+        assert_eq!(right_info.update_count, 2);
+        assert_eq!(reply.channels[0].value_type.as_deref(), Some("int32"));
+        assert_eq!(reply.channels[0].update_count, 1);
+    }
+
+    #[test]
+    fn removes_channels() {
+        let context = Context::new();
+        let pub_socket = pub_socket(&context, "inproc://removals");
+        let sub_socket = context.socket(zmq::SUB).unwrap();
+        sub_socket.connect("inproc://removals").unwrap();
+        sub_socket
+            .set_subscribe(topic::REMOVAL_TOPIC.as_bytes())
+            .unwrap();
+        let state = state();
+        push(
+            &state,
+            &pub_socket,
+            "drive/speed",
+            supported_values::Kind::Double(1.0),
+        );
+        push(
+            &state,
+            &pub_socket,
+            "arm/angle",
+            supported_values::Kind::Double(0.5),
+        );
+        let removal = || {
+            let frames = sub_socket.recv_multipart(0).unwrap();
+            assert_eq!(frames[0], topic::REMOVAL_TOPIC.as_bytes());
+            match Publish::decode(&frames[1][..]).unwrap().payload {
+                Some(publish::Payload::Removal(removal)) => (removal.channel, removal.deleted),
+                payload => panic!("expected a removal, got {:?}", payload),
+            }
+        };
+
+        let clear = request::Payload::Clear(ClearChannelCommand {
+            channel: String::from("drive/speed"),
+        });
+        assert!(matches!(
+            XTablesServer::handle_request(&state, &pub_socket, clear, "", PROTOCOL_VERSION),
+            reply::Payload::Ack(_)
+        ));
+        assert_eq!(removal(), (String::from("drive/speed"), false));
+        {
+            let channels = state.cached_messages.lock().unwrap();
+            assert!(channels["drive/speed"].latest().is_none());
+        }
+        // a cleared channel keeps the type it was locked to
+        assert!(state.schemas.lock().unwrap().get("drive/speed").is_some());
+
+        let delete = request::Payload::Delete(DeleteChannelCommand {
+            channel: String::from("arm/angle"),
+        });
+        assert!(matches!(
+            XTablesServer::handle_request(&state, &pub_socket, delete, "", PROTOCOL_VERSION),
+            reply::Payload::Ack(_)
+        ));
+        assert_eq!(removal(), (String::from("arm/angle"), true));
+        assert!(
+            !state
+                .cached_messages
+                .lock()
+                .unwrap()
+                .contains_key("arm/angle")
+        );
+        assert!(state.schemas.lock().unwrap().get("arm/angle").is_none());
+        // so it takes a value of another type when recreated
+        push(
+            &state,
+            &pub_socket,
+            "arm/angle",
+            supported_values::Kind::Bool(true),
+        );
+    }
+
+    #[test]
+    fn compare_and_set() {
+        let context = Context::new();
+        let pub_socket = pub_socket(&context, "inproc://compare-and-set");
+        let state = state();
+        let int = |value| {
+            Some(SupportedValues {
+                kind: Some(supported_values::Kind::Int32(value)),
+            })
+        };
+        // whether the value was written and the sequence of the latest value afterwards, every
+        // rejected write below leaves the channel holding 1
+        let cas = |channel: &str, value, expected| {
+            let payload = request::Payload::CompareAndSet(CompareAndSetCommand {
+                channel: channel.to_string(),
+                value: int(value),
+                expected,
+            });
+            match XTablesServer::handle_request(&state, &pub_socket, payload, "", PROTOCOL_VERSION)
+            {
+                reply::Payload::Write(reply) => (
+                    reply.applied,
+                    reply.current.map(|current| {
+                        assert_eq!(current.value, int(if reply.applied { value } else { 1 }));
+                        current.sequence
+                    }),
+                ),
+                payload => panic!("unexpected reply {:?}", payload),
+            }
+        };
+        let by_value = |value| {
+            Some(compare_and_set_command::Expected::ExpectedValue(
+                int(value).unwrap(),
+            ))
+        };
+        let by_sequence = |sequence| {
+            Some(compare_and_set_command::Expected::ExpectedSequence(
+                sequence,
+            ))
+        };
+
+        // a missing channel only matches sequence 0
+        assert_eq!(cas("arm/mode", 1, by_value(0)), (false, None));
+        assert_eq!(cas("arm/mode", 1, by_sequence(1)), (false, None));
+        assert_eq!(cas("arm/mode", 1, by_sequence(0)), (true, Some(1)));
+
+        assert_eq!(cas("arm/mode", 2, by_value(3)), (false, Some(1)));
+        assert_eq!(cas("arm/mode", 2, by_sequence(2)), (false, Some(1)));
+        assert_eq!(cas("arm/mode", 2, by_value(1)), (true, Some(2)));
+        push(
+            &state,
+            &pub_socket,
+            "arm/mode",
+            supported_values::Kind::Int32(1),
+        );
+        assert_eq!(cas("arm/mode", 4, by_sequence(3)), (true, Some(4)));
+
+        // so does a cleared one, whose sequence numbers still continue
+        XTablesServer::handle_removal(&state, &pub_socket, "arm/mode", false);
+        assert_eq!(cas("arm/mode", 1, by_sequence(4)), (false, None));
+        assert_eq!(cas("arm/mode", 1, by_sequence(0)), (true, Some(5)));
+
+        let payload = request::Payload::CompareAndSet(CompareAndSetCommand {
+            channel: String::from("arm/mode"),
+            value: int(2),
+            expected: None,
+        });
+        assert!(matches!(
+            XTablesServer::handle_request(&state, &pub_socket, payload, "", PROTOCOL_VERSION),
+            reply::Payload::Error(_)
+        ));
+        let channels = state.cached_messages.lock().unwrap();
+        assert_eq!(channels["arm/mode"].latest().unwrap().sequence, 5);
+    }
+
+    #[test]
+    fn acknowledges_sets() {
+        let context = Context::new();
+        let pub_socket = pub_socket(&context, "inproc://sets");
+        let state = state();
+        let set = |kind| {
+            let payload = request::Payload::Set(SendDataCommand {
+                channel: String::from("auto/step"),
+                value: Some(SupportedValues { kind: Some(kind) }),
+                source_timestamp: Some(7),
+            });
+            XTablesServer::handle_request(&state, &pub_socket, payload, "", PROTOCOL_VERSION)
+        };
+
+        push(
+            &state,
+            &pub_socket,
+            "auto/step",
+            supported_values::Kind::Int64(1),
+        );
+        let reply::Payload::Write(reply) = set(supported_values::Kind::Int64(2)) else {
+            panic!("expected a write reply");
+        };
+        assert!(reply.applied);
+        let current = reply.current.unwrap();
+        assert_eq!(current.sequence, 2);
+        assert_eq!(current.source_timestamp, Some(7));
+        assert_eq!(
+            current.value.unwrap().kind,
+            Some(supported_values::Kind::Int64(2))
+        );
+        {
+            let channels = state.cached_messages.lock().unwrap();
+            let latest = channels["auto/step"].latest().unwrap();
+            assert_eq!(latest.kind, supported_values::Kind::Int64(2));
+            assert_eq!(latest.sequence, 2);
+        }
+
+        assert!(matches!(
+            set(supported_values::Kind::Bool(true)),
+            reply::Payload::Error(_)
+        ));
+    }
+
+    #[test]
+    fn splits_envelopes() {
+        let identity = b"client".to_vec();
+        let request = b"request".to_vec();
+
+        assert_eq!(
+            XTablesServer::split_envelope(vec![identity.clone(), request.clone()]),
+            Some((vec![identity.clone()], request.clone()))
+        );
+        assert_eq!(
+            XTablesServer::split_envelope(vec![identity.clone(), Vec::new(), request.clone()]),
+            Some((vec![identity.clone(), Vec::new()], request.clone()))
+        );
+        assert_eq!(
+            XTablesServer::split_envelope(vec![identity.clone(), b"x".to_vec(), request]),
+            None
+        );
+        assert_eq!(XTablesServer::split_envelope(vec![identity]), None);
+        assert_eq!(XTablesServer::split_envelope(Vec::new()), None);
+    }
+}
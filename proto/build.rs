@@ -1,3 +1,7 @@
 fn main() {
-    prost_build::compile_protos(&["proto/messages.proto"], &["proto"]).unwrap();
+    // ordered maps keep map values comparable and their fields in a stable order in logs
+    prost_build::Config::new()
+        .btree_map(["."])
+        .compile_protos(&["proto/messages.proto"], &["proto"])
+        .unwrap();
 }
@@ -1,3 +1,44 @@
+/// Version of the request/reply protocol, sent with every request and reply. Peers that
+/// predate versioning leave the field unset and read as 0.
+///
+/// 2: replies to `GetDataCommand` carry `not_found` instead of a sentinel string value.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Value servers before protocol version 2 reply with when a channel holds no value.
+pub const LEGACY_NO_DATA: &str = "XTABLES_INTERNAL_NO_DATA_AVAILABLE";
+
+pub mod topic;
+
 pub mod protobuf {
     include!(concat!(env!("OUT_DIR"), "/protobuf.rs"));
+
+    impl supported_values::Kind {
+        /// Every name `type_name` can return.
+        pub const TYPE_NAMES: &'static [&'static str] = &[
+            "string", "int32", "int64", "uint32", "uint64", "bool", "double", "float", "bytes",
+            "struct", "double[]", "float[]", "int64[]", "bool[]", "string[]", "map",
+        ];
+
+        /// Name of the type of the value as reported to clients listing channels.
+        pub fn type_name(&self) -> &'static str {
+            match self {
+                supported_values::Kind::String(_) => "string",
+                supported_values::Kind::Int32(_) => "int32",
+                supported_values::Kind::Int64(_) => "int64",
+                supported_values::Kind::Uint32(_) => "uint32",
+                supported_values::Kind::Uint64(_) => "uint64",
+                supported_values::Kind::Bool(_) => "bool",
+                supported_values::Kind::Double(_) => "double",
+                supported_values::Kind::Float(_) => "float",
+                supported_values::Kind::Bytes(_) => "bytes",
+                supported_values::Kind::StructValue(_) => "struct",
+                supported_values::Kind::DoubleArray(_) => "double[]",
+                supported_values::Kind::FloatArray(_) => "float[]",
+                supported_values::Kind::Int64Array(_) => "int64[]",
+                supported_values::Kind::BoolArray(_) => "bool[]",
+                supported_values::Kind::StringArray(_) => "string[]",
+                supported_values::Kind::MapValue(_) => "map",
+            }
+        }
+    }
 }
@@ -0,0 +1,56 @@
+//! Topics values are published under. Channel names are `/` separated paths such as
+//! `drive/left/speed`.
+//!
+//! Values are published under the bare channel name and logs under [`LOG_TOPIC`], as servers
+//! always have, so clients of every version keep receiving them. SUB sockets match topics by
+//! prefix: a client subscribed to `drive` also receives `drivetrain` and filters it out.
+//! Payloads older clients cannot decode go out under the internal topics below instead, which
+//! end in [`TOPIC_TERMINATOR`] and so are never matched by a channel subscription.
+
+/// Ends the internal topics, no channel name contains it.
+pub const TOPIC_TERMINATOR: char = '\0';
+
+pub const LOG_TOPIC: &str = "XTABLES_INTERNAL_LOG";
+
+/// Batches carry values of many channels, so they are published under a topic of their own.
+pub const BATCH_TOPIC: &str = "XTABLES_INTERNAL_BATCH\0";
+
+/// Deletes and clears of every channel.
+pub const REMOVAL_TOPIC: &str = "XTABLES_INTERNAL_REMOVAL\0";
+
+pub const HEARTBEAT_TOPIC: &str = "XTABLES_INTERNAL_HEARTBEAT\0";
+
+/// Whether `segment` can be part of a channel name: not empty and without wildcards.
+pub fn is_valid_segment(segment: &str) -> bool {
+    !segment.is_empty() && !segment.contains(['*', TOPIC_TERMINATOR])
+}
+
+/// Whether `channel` is made of non-empty segments without wildcards.
+pub fn is_valid_channel(channel: &str) -> bool {
+    channel.split('/').all(is_valid_segment)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channels() {
+        assert!(is_valid_channel("drive"));
+        assert!(is_valid_channel("drive/left/speed"));
+        assert!(!is_valid_channel(""));
+        assert!(!is_valid_channel("/drive"));
+        assert!(!is_valid_channel("drive/"));
+        assert!(!is_valid_channel("drive//left"));
+        assert!(!is_valid_channel("drive/*"));
+        assert!(!is_valid_channel("drive\0"));
+    }
+
+    #[test]
+    fn internal_topics() {
+        for topic in [BATCH_TOPIC, REMOVAL_TOPIC, HEARTBEAT_TOPIC] {
+            assert!(topic.ends_with(TOPIC_TERMINATOR));
+            assert!(!is_valid_channel(topic));
+        }
+    }
+}